@@ -0,0 +1,358 @@
+//! 基于 tokio 的异步接口，需要启用 `async` feature。
+
+use crate::cipher::{self, PositionCipher};
+use crate::convert::{self, ConvertReport, NamingStrategy, OutputTarget};
+use crate::decryptor::SNIFF_LEN;
+use crate::keys::KeySet;
+use crate::metadata::NcmMetadata;
+use crate::ncm::{BUFFER_SIZE, NcmCipher, NcmError, NcmHeader, NcmInfo};
+use crate::parser::{self, Incomplete, ParsedNcm};
+use crate::{sniff, tag};
+use std::io::{self, SeekFrom};
+use std::ops::Deref;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+
+/// 异步读取 NCM 文件头、元数据和封面，对应同步版本的 [`crate::NcmFile`]，
+/// 同样通过 [`Deref`] 提供 [`NcmInfo`] 的方法
+pub struct AsyncNcmFile<R> {
+    reader: R,
+    info: NcmInfo,
+}
+
+impl AsyncNcmFile<fs::File> {
+    /// 打开并解析指定路径的 NCM 文件
+    pub async fn open(path: &Path) -> Result<Self, NcmError> {
+        Self::from_reader(fs::File::open(path).await?).await
+    }
+
+    /// 打开并解析指定路径的 NCM 文件，依次尝试 `key_sets` 中的密钥组
+    pub async fn open_with_keys(path: &Path, key_sets: &[KeySet]) -> Result<Self, NcmError> {
+        Self::from_reader_with_keys(fs::File::open(path).await?, key_sets).await
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncNcmFile<R> {
+    /// 从异步数据流解析 NCM，游标需位于 NCM 数据开头
+    pub async fn from_reader(reader: R) -> Result<Self, NcmError> {
+        Self::from_reader_with_keys(reader, &[KeySet::default()]).await
+    }
+
+    /// 从异步数据流解析 NCM，依次尝试 `key_sets` 中的密钥组
+    pub async fn from_reader_with_keys(
+        mut reader: R,
+        key_sets: &[KeySet],
+    ) -> Result<Self, NcmError> {
+        let parsed = parse_ncm_file(&mut reader, key_sets).await?;
+        let cipher = NcmCipher::new(&parsed.header.key);
+
+        // 识别音频数据的容器格式
+        let head = read_audio_head(&mut reader, parsed.header.audio_offset, &cipher).await?;
+        let container = sniff::sniff_container(&head);
+
+        Ok(Self {
+            reader,
+            info: NcmInfo::new(parsed, cipher, container),
+        })
+    }
+
+    /// 返回解密后音频数据的异步读取器，支持 `AsyncRead + AsyncSeek`
+    pub async fn audio(&mut self) -> Result<AsyncNcmAudioReader<&mut R>, NcmError> {
+        let audio_offset = self.info.header.audio_offset;
+        self.reader.seek(SeekFrom::Start(audio_offset)).await?;
+        Ok(AsyncNcmAudioReader {
+            inner: &mut self.reader,
+            cipher: self.info.cipher.clone(),
+            audio_offset,
+            pos: 0,
+            rejected: None,
+        })
+    }
+
+    /// 消耗自身，返回解密后音频数据的异步读取器
+    pub async fn into_audio(mut self) -> Result<AsyncNcmAudioReader<R>, NcmError> {
+        let audio_offset = self.info.header.audio_offset;
+        self.reader.seek(SeekFrom::Start(audio_offset)).await?;
+        Ok(AsyncNcmAudioReader {
+            inner: self.reader,
+            cipher: self.info.cipher,
+            audio_offset,
+            pos: 0,
+            rejected: None,
+        })
+    }
+}
+
+impl<R> Deref for AsyncNcmFile<R> {
+    type Target = NcmInfo;
+
+    fn deref(&self) -> &NcmInfo {
+        &self.info
+    }
+}
+
+/// 解密音频数据的异步读取器，行为与 [`crate::NcmAudioReader`] 相同
+pub struct AsyncNcmAudioReader<R> {
+    inner: R,
+    cipher: NcmCipher,
+    audio_offset: u64,
+    pos: u64,
+    // 定位到音频数据开头之前后，正在回到原来的位置，完成后返回该错误
+    rejected: Option<io::Error>,
+}
+
+impl<R> AsyncNcmAudioReader<R> {
+    /// 当前在音频数据中的位置
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// 取回内部的读取器
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncNcmAudioReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let filled = &mut buf.filled_mut()[start..];
+        this.cipher.decrypt(this.pos, filled);
+        this.pos += filled.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for AsyncNcmAudioReader<R> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let target = cipher::inner_seek_target(this.audio_offset, None, position)?;
+        Pin::new(&mut this.inner).start_seek(target)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            let absolute = ready!(Pin::new(&mut this.inner).poll_complete(cx))?;
+            if let Some(e) = this.rejected.take() {
+                return Poll::Ready(Err(e));
+            }
+            match cipher::audio_position(this.audio_offset, absolute) {
+                Ok(pos) => {
+                    this.pos = pos;
+                    return Poll::Ready(Ok(pos));
+                }
+                Err(e) => {
+                    // 回到原来的位置，保持读取器状态不变，完成后再返回错误
+                    Pin::new(&mut this.inner)
+                        .start_seek(SeekFrom::Start(this.audio_offset + this.pos))?;
+                    this.rejected = Some(e);
+                }
+            }
+        }
+    }
+}
+
+/// 异步读取 NCM 文件头、元数据和封面区块中的图片，与同步版本共用解析逻辑，依次尝试 `key_sets` 中的密钥组
+pub async fn read_ncm_header<R: AsyncRead + AsyncSeek + Unpin>(
+    file: &mut R,
+    key_sets: &[KeySet],
+) -> Result<(NcmHeader, NcmMetadata, Vec<Vec<u8>>), NcmError> {
+    let parsed = parse_ncm_file(file, key_sets).await?;
+    Ok((parsed.header, parsed.metadata, parsed.images))
+}
+
+async fn parse_ncm_file<R: AsyncRead + AsyncSeek + Unpin>(
+    file: &mut R,
+    key_sets: &[KeySet],
+) -> Result<ParsedNcm, NcmError> {
+    let base = file.stream_position().await?;
+    let mut file_len = file.seek(SeekFrom::End(0)).await?;
+    let mut data = Vec::new();
+
+    let preamble = parse_with(file, &mut data, base, &mut file_len, |data, file_len| {
+        parser::parse_preamble(data, base, file_len, key_sets)
+    })
+    .await?;
+
+    let mut heads = Vec::new();
+    for candidate in preamble.audio_offset_candidates(file_len) {
+        file.seek(SeekFrom::Start(candidate)).await?;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        (&mut *file)
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await?;
+        heads.push((candidate, head));
+    }
+    let audio_offset = preamble.select_audio_offset(&heads)?;
+
+    parse_with(file, &mut data, base, &mut file_len, |data, file_len| {
+        preamble.finish(data, base, file_len, audio_offset)
+    })
+    .await
+}
+
+/// 反复调用 `parse`，数据不足时从文件中补充，与同步版本的同名函数相同
+async fn parse_with<R: AsyncRead + AsyncSeek + Unpin, T>(
+    file: &mut R,
+    data: &mut Vec<u8>,
+    base: u64,
+    file_len: &mut u64,
+    mut parse: impl FnMut(&[u8], u64) -> Result<T, Incomplete>,
+) -> Result<T, NcmError> {
+    loop {
+        match parse(data, *file_len) {
+            Ok(value) => return Ok(value),
+            Err(Incomplete::NeedMore(end)) => {
+                let have = base + data.len() as u64;
+                let target = parser::read_target(have, end, *file_len);
+                file.seek(SeekFrom::Start(have)).await?;
+                (&mut *file).take(target - have).read_to_end(data).await?;
+                if base + (data.len() as u64) < target {
+                    *file_len = base + data.len() as u64;
+                }
+            }
+            Err(Incomplete::Failed(e)) => return Err(e),
+        }
+    }
+}
+
+/// 读取并解密 `offset` 处开始的最多 [`SNIFF_LEN`] 个字节，用于识别容器格式
+async fn read_audio_head<R: AsyncRead + AsyncSeek + Unpin>(
+    file: &mut R,
+    offset: u64,
+    cipher: &NcmCipher,
+) -> Result<Vec<u8>, NcmError> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head).await?;
+    cipher.decrypt(0, &mut head);
+    Ok(head)
+}
+
+/// [`crate::decrypt_and_dump`] 的异步版本，通过 tokio 的文件接口读写，只支持 NCM 文件。
+///
+/// `skip` 为 `true` 且输出已存在时不做转换，返回的 [`ConvertReport::skipped`] 为 `true`
+pub async fn decrypt_and_dump(
+    input_path: &Path,
+    output_path: Option<&Path>,
+    skip: bool,
+) -> Result<ConvertReport, NcmError> {
+    let mut ncm = AsyncNcmFile::open(input_path).await?;
+    let format = ncm.format();
+
+    let target = match output_path {
+        Some(p) => OutputTarget::Path(p.to_path_buf()),
+        None => OutputTarget::DefaultDir,
+    };
+    let final_output_path = convert::resolve_output_path(
+        input_path,
+        &target,
+        &NamingStrategy::InputStem,
+        &ncm.metadata,
+        &format,
+    )?;
+
+    let warnings = ncm.warnings();
+    let mut report = ConvertReport {
+        output_path: final_output_path,
+        format,
+        version: Some(ncm.header.version.to_string()),
+        key_set: Some(ncm.header.key_set.clone()),
+        bytes_written: 0,
+        tags_written: Vec::new(),
+        cover_path: None,
+        skipped: false,
+        warnings: warnings.iter().map(ToString::to_string).collect(),
+    };
+
+    if skip && fs::try_exists(&report.output_path).await? {
+        report.skipped = true;
+        return Ok(report);
+    }
+
+    // 如果目录不存在，则创建
+    if let Some(parent) = report.output_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    // 在内存中解密并写入标签，避免在异步上下文中调用阻塞的文件标签接口
+    let mut audio = Vec::with_capacity(BUFFER_SIZE);
+    ncm.audio().await?.read_to_end(&mut audio).await?;
+    let (audio, tags_written) =
+        tag::tag_bytes_written(audio, &report.format, &ncm.metadata, ncm.cover())?;
+
+    fs::write(&report.output_path, &audio).await?;
+    report.bytes_written = audio.len() as u64;
+    report.tags_written = tags_written;
+    if !tag::supports_format(&report.format) {
+        report
+            .warnings
+            .push(format!("不支持为 {} 格式写入标签", report.format));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncm::tests::{COVER, encode_sample, sample_audio, sample_metadata};
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn read_and_seek() {
+        let data = encode_sample(Some(COVER));
+        let sync = crate::NcmFile::from_reader(Cursor::new(&data)).unwrap();
+        let mut ncm = AsyncNcmFile::from_reader(Cursor::new(data.clone()))
+            .await
+            .unwrap();
+        assert_eq!(ncm.header().audio_offset, sync.header().audio_offset);
+        assert_eq!(ncm.header().key, sync.header().key);
+        assert_eq!(ncm.metadata(), &sample_metadata());
+        assert_eq!(ncm.raw_metadata(), sync.raw_metadata());
+        assert_eq!(ncm.cover(), Some(COVER));
+        assert_eq!(ncm.container(), Some("mp3"));
+        assert!(ncm.warnings().is_empty());
+
+        let expected = sample_audio();
+        let mut audio = ncm.audio().await.unwrap();
+        let mut buf = Vec::new();
+        audio.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+
+        let mut buf = [0; 16];
+        assert_eq!(audio.seek(SeekFrom::Start(100)).await.unwrap(), 100);
+        audio.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected[100..116]);
+        assert_eq!(audio.seek(SeekFrom::Current(-50)).await.unwrap(), 66);
+        audio.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected[66..82]);
+        let end = expected.len() as u64 - 16;
+        assert_eq!(audio.seek(SeekFrom::End(-16)).await.unwrap(), end);
+        audio.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected[expected.len() - 16..]);
+
+        // 定位到音频数据开头之前时返回错误，位置保持不变
+        audio.seek(SeekFrom::Start(200)).await.unwrap();
+        let err = audio.seek(SeekFrom::Current(-1000)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(audio.position(), 200);
+        audio.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected[200..216]);
+
+        let mut audio = ncm.into_audio().await.unwrap();
+        let mut buf = Vec::new();
+        audio.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+    }
+}
@@ -0,0 +1,218 @@
+//! 按位置解密的音频读取器，由 NCM、QMC 和 KGM 共用。
+//!
+//! 这几种格式的音频数据都可以按字节在音频中的偏移独立解密，读取器只需要记录音频数据在文件中的起始位置
+//! 和当前位置，就可以任意定位后再读取。
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// 按字节在音频数据中的偏移解密的算法
+pub trait PositionCipher {
+    /// 原地解密 `buf`，`offset` 为 `buf` 首字节在音频数据中的偏移
+    fn decrypt(&self, offset: u64, buf: &mut [u8]);
+}
+
+/// 解密音频数据的读取器。
+///
+/// 包装整个加密文件，对外只暴露音频部分：位置 0 对应文件中 `audio_offset` 处的字节。
+/// 指定了音频长度时，读取到该长度为止，[`SeekFrom::End`] 也相对于音频数据的末尾；
+/// 否则音频数据一直到文件末尾。
+pub struct CipherReader<R, C> {
+    pub(crate) inner: R,
+    pub(crate) cipher: C,
+    pub(crate) audio_offset: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: Option<u64>,
+}
+
+impl<R: Seek, C> CipherReader<R, C> {
+    /// 创建读取器，并将 `inner` 定位到 `audio_offset` 处的音频数据开头
+    pub fn new_at(
+        mut inner: R,
+        cipher: C,
+        audio_offset: u64,
+        len: Option<u64>,
+    ) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(audio_offset))?;
+        Ok(Self {
+            inner,
+            cipher,
+            audio_offset,
+            pos: 0,
+            len,
+        })
+    }
+}
+
+impl<R, C> CipherReader<R, C> {
+    /// 当前在音频数据中的位置
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// 取回内部的读取器
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read, C: PositionCipher> Read for CipherReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.len.map_or(buf.len(), |len| {
+            buf.len().min(len.saturating_sub(self.pos) as usize)
+        });
+        let n = self.inner.read(&mut buf[..max])?;
+        self.cipher.decrypt(self.pos, &mut buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek, C> Seek for CipherReader<R, C> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = inner_seek_target(self.audio_offset, self.len, pos)?;
+        let absolute = self.inner.seek(target)?;
+        match audio_position(self.audio_offset, absolute) {
+            Ok(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            Err(e) => {
+                // 回到原来的位置，保持读取器状态不变
+                self.inner
+                    .seek(SeekFrom::Start(self.audio_offset + self.pos))?;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// 将音频数据中的定位换算为在整个文件中的定位
+pub(crate) fn inner_seek_target(
+    audio_offset: u64,
+    len: Option<u64>,
+    pos: SeekFrom,
+) -> io::Result<SeekFrom> {
+    let start = match (pos, len) {
+        (SeekFrom::Start(n), _) => n,
+        (SeekFrom::End(delta), Some(len)) => len
+            .checked_add_signed(delta)
+            .ok_or_else(|| seek_error(delta < 0))?,
+        (other, _) => return Ok(other),
+    };
+    audio_offset
+        .checked_add(start)
+        .map(SeekFrom::Start)
+        .ok_or_else(|| seek_error(false))
+}
+
+/// 由文件中的位置得到在音频数据中的位置，位于音频数据之前时返回错误
+pub(crate) fn audio_position(audio_offset: u64, absolute: u64) -> io::Result<u64> {
+    absolute
+        .checked_sub(audio_offset)
+        .ok_or_else(|| seek_error(true))
+}
+
+fn seek_error(before_start: bool) -> io::Error {
+    let message = if before_start {
+        "不能定位到音频数据开头之前"
+    } else {
+        "定位超出范围"
+    };
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// 每个字节与其在音频数据中偏移的低 8 位异或
+    struct OffsetCipher;
+
+    impl PositionCipher for OffsetCipher {
+        fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte ^= (offset + i as u64) as u8;
+            }
+        }
+    }
+
+    const AUDIO_OFFSET: u64 = 10;
+    const AUDIO_LEN: u64 = 1000;
+
+    fn audio() -> Vec<u8> {
+        (0..AUDIO_LEN).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// 10 字节文件头、加密的音频数据和 6 字节末尾数据
+    fn reader(len: Option<u64>) -> CipherReader<Cursor<Vec<u8>>, OffsetCipher> {
+        let mut audio = audio();
+        OffsetCipher.decrypt(0, &mut audio);
+        let mut data = vec![0xAA; AUDIO_OFFSET as usize];
+        data.extend(audio);
+        data.extend_from_slice(b"TRAILR");
+        CipherReader::new_at(Cursor::new(data), OffsetCipher, AUDIO_OFFSET, len).unwrap()
+    }
+
+    fn read4<R: Read>(reader: &mut R) -> [u8; 4] {
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn at(offset: u64) -> [u8; 4] {
+        let offset = offset as usize;
+        audio()[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn seek_then_read() {
+        let mut reader = reader(None);
+        assert_eq!(read4(&mut reader), at(0));
+        assert_eq!(reader.seek(SeekFrom::Start(500)).unwrap(), 500);
+        assert_eq!(read4(&mut reader), at(500));
+        assert_eq!(reader.seek(SeekFrom::Current(-104)).unwrap(), 400);
+        assert_eq!(read4(&mut reader), at(400));
+        assert_eq!(reader.seek(SeekFrom::Current(96)).unwrap(), 500);
+        assert_eq!(read4(&mut reader), at(500));
+        // 没有指定长度时，末尾是整个文件的末尾
+        assert_eq!(reader.seek(SeekFrom::End(-6)).unwrap(), AUDIO_LEN);
+        assert_eq!(reader.position(), AUDIO_LEN);
+    }
+
+    #[test]
+    fn rejected_seek_keeps_position() {
+        let mut reader = reader(None);
+        reader.seek(SeekFrom::Start(300)).unwrap();
+        let err = reader.seek(SeekFrom::Current(-301)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(reader.position(), 300);
+        assert_eq!(read4(&mut reader), at(300));
+
+        // 换算到文件中的位置时溢出
+        let err = reader.seek(SeekFrom::Start(u64::MAX)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(reader.position(), 304);
+        assert_eq!(read4(&mut reader), at(304));
+    }
+
+    #[test]
+    fn seek_end_with_len() {
+        let mut reader = reader(Some(AUDIO_LEN));
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), AUDIO_LEN - 4);
+        assert_eq!(read4(&mut reader), at(AUDIO_LEN - 4));
+        // 读取到音频长度为止，不包括末尾数据
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), AUDIO_LEN);
+        let err = reader
+            .seek(SeekFrom::End(-(AUDIO_LEN as i64) - 1))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(reader.position(), AUDIO_LEN);
+        assert_eq!(reader.seek(SeekFrom::End(-(AUDIO_LEN as i64))).unwrap(), 0);
+        assert_eq!(read4(&mut reader), at(0));
+    }
+}
@@ -0,0 +1,469 @@
+use crate::decryptor::{OpenedTrack, Registry};
+use crate::keys::KeySet;
+use crate::metadata::NcmMetadata;
+use crate::ncm::{self, BUFFER_SIZE, DEFAULT_DIR_UNDER_HOME, NcmError, stream_len};
+use crate::progress::{CancellationToken, Phase, Progress};
+use crate::tag;
+use std::env::home_dir;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// 输出位置
+#[derive(Debug, Clone, Default)]
+pub enum OutputTarget {
+    /// 输出到 ~/Instrumental，文件名由 [`NamingStrategy`] 决定
+    #[default]
+    DefaultDir,
+    /// 输出到指定目录，文件名由 [`NamingStrategy`] 决定
+    Dir(PathBuf),
+    /// 输出到指定路径，扩展名会被替换为实际的音频格式
+    Path(PathBuf),
+}
+
+/// 输出文件已存在时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// 覆盖已有文件
+    #[default]
+    Overwrite,
+    /// 跳过转换，报告中 `skipped` 为 `true`
+    Skip,
+    /// 返回 [`NcmError::OutputExists`]
+    Error,
+}
+
+/// 封面的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoverPolicy {
+    /// 嵌入音频标签（需要开启标签写入）
+    #[default]
+    Embed,
+    /// 保存为与输出同名的图片文件，不嵌入标签
+    Sidecar,
+    /// 忽略封面
+    Omit,
+}
+
+/// 自定义命名函数，参数为输入路径和元数据，返回不含扩展名的文件名
+pub type NameFn<'a> = Box<dyn Fn(&Path, &NcmMetadata) -> String + 'a>;
+
+/// 输出文件名（不含扩展名）的生成方式
+#[derive(Default)]
+pub enum NamingStrategy<'a> {
+    /// 与输入文件同名
+    #[default]
+    InputStem,
+    /// `艺术家 - 曲名`
+    ArtistTitle,
+    /// 歌曲 ID，适合没有元数据的缓存文件，没有 ID 时与输入文件同名
+    SongId,
+    /// 自定义，参数为输入路径和元数据
+    Custom(NameFn<'a>),
+}
+
+impl NamingStrategy<'_> {
+    fn file_stem(&self, input_path: &Path, metadata: &NcmMetadata) -> String {
+        let input_stem = || {
+            input_path
+                .file_stem()
+                // 如果没有文件名，则使用默认名称
+                .map_or_else(
+                    || "unnamed_file".to_string(),
+                    |s| s.to_string_lossy().into_owned(),
+                )
+        };
+        let stem = match self {
+            NamingStrategy::InputStem => input_stem(),
+            NamingStrategy::ArtistTitle => format!(
+                "{} - {}",
+                metadata.artist_names().join(", "),
+                metadata.title()
+            ),
+            NamingStrategy::SongId => metadata
+                .music_id
+                .map_or_else(input_stem, |id| id.to_string()),
+            NamingStrategy::Custom(f) => f(input_path, metadata),
+        };
+        sanitize_file_name(&stem)
+    }
+}
+
+/// 替换文件名中各平台不允许的字符
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim();
+    if name.is_empty() {
+        "unnamed_file".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// 转换选项，使用构建器方法设置。
+///
+/// ```no_run
+/// use ncmcvt::{ConvertOptions, OverwritePolicy, convert};
+///
+/// let mut options = ConvertOptions::new()
+///     .output_dir("out")
+///     .overwrite(OverwritePolicy::Skip);
+/// let report = convert("song.ncm".as_ref(), &mut options)?;
+/// println!("{}", report.output_path.display());
+/// # Ok::<(), ncmcvt::NcmError>(())
+/// ```
+#[derive(Default)]
+pub struct ConvertOptions<'a> {
+    target: OutputTarget,
+    overwrite: OverwritePolicy,
+    skip_tagging: bool,
+    strict: bool,
+    write_163_key: bool,
+    cover: CoverPolicy,
+    naming: NamingStrategy<'a>,
+    progress: Option<Box<dyn FnMut(Progress) + 'a>>,
+    cancel: Option<CancellationToken>,
+    registry: Option<&'a Registry>,
+}
+
+impl<'a> ConvertOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置输出位置，默认为 [`OutputTarget::DefaultDir`]
+    pub fn target(mut self, target: OutputTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// 输出到指定目录
+    pub fn output_dir(self, dir: impl Into<PathBuf>) -> Self {
+        self.target(OutputTarget::Dir(dir.into()))
+    }
+
+    /// 输出到指定路径，扩展名会被替换为实际的音频格式
+    pub fn output_path(self, path: impl Into<PathBuf>) -> Self {
+        self.target(OutputTarget::Path(path.into()))
+    }
+
+    /// 设置输出文件已存在时的处理方式，默认覆盖
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Self {
+        self.overwrite = policy;
+        self
+    }
+
+    /// 是否写入音频标签，默认写入
+    pub fn tagging(mut self, enabled: bool) -> Self {
+        self.skip_tagging = !enabled;
+        self
+    }
+
+    /// 严格模式：将未知版本、封面区块异常等警告作为错误返回，默认关闭
+    pub fn strict(mut self, enabled: bool) -> Self {
+        self.strict = enabled;
+        self
+    }
+
+    /// 是否写入客户端格式的 `163 key(Don't modify):` 注释，让客户端继续识别转换后的文件，
+    /// 默认不写入。需要开启标签写入，且元数据中有歌曲 ID
+    pub fn write_163_key(mut self, enabled: bool) -> Self {
+        self.write_163_key = enabled;
+        self
+    }
+
+    /// 设置封面的处理方式，默认嵌入标签
+    pub fn cover(mut self, policy: CoverPolicy) -> Self {
+        self.cover = policy;
+        self
+    }
+
+    /// 设置输出文件名的生成方式，仅在未指定确切输出路径时生效
+    pub fn naming(mut self, naming: NamingStrategy<'a>) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    /// 设置进度回调，在每个阶段开始时以及每解密一块音频数据后调用
+    pub fn on_progress(mut self, callback: impl FnMut(Progress) + 'a) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// 设置取消令牌，在处理每块数据前检查
+    pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// 使用自定义的解密器注册表，默认为 [`Registry::default`]
+    pub fn registry(mut self, registry: &'a Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    fn report(&mut self, phase: Phase, processed: u64, total: u64) {
+        if let Some(callback) = self.progress.as_mut() {
+            callback(Progress {
+                phase,
+                processed,
+                total,
+            });
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|t| t.is_cancelled())
+    }
+}
+
+/// 一次转换的结果
+#[derive(Debug, Clone)]
+pub struct ConvertReport {
+    pub output_path: PathBuf,
+    /// 音频格式（小写，如 `mp3`、`flac`）
+    pub format: String,
+    /// 加密文件的格式版本（如果有）
+    pub version: Option<String>,
+    /// 解密所用的密钥组名称（如果有）
+    pub key_set: Option<String>,
+    /// 输出文件的大小，跳过时为 0
+    pub bytes_written: u64,
+    /// 实际写入的标签名称，如 `title`、`cover`
+    pub tags_written: Vec<&'static str>,
+    /// 封面图片另存的路径
+    pub cover_path: Option<PathBuf>,
+    /// 输出文件已存在而跳过了转换
+    pub skipped: bool,
+    /// 不影响输出但值得注意的问题
+    pub warnings: Vec<String>,
+}
+
+/// 计算输出路径
+pub(crate) fn resolve_output_path(
+    input_path: &Path,
+    target: &OutputTarget,
+    naming: &NamingStrategy,
+    metadata: &NcmMetadata,
+    format: &str,
+) -> Result<PathBuf, NcmError> {
+    let dir = match target {
+        OutputTarget::Path(p) => return Ok(p.with_extension(format)),
+        OutputTarget::Dir(dir) => dir.clone(),
+        OutputTarget::DefaultDir => {
+            let home_dir = home_dir().ok_or_else(|| {
+                NcmError::FileIo(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "无法获取用户的 ~ 目录",
+                ))
+            })?;
+            let default_dir = home_dir.join(DEFAULT_DIR_UNDER_HOME);
+            if !default_dir.exists() {
+                return Err(NcmError::FileIo(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("目录不存在: {}", default_dir.display()),
+                )));
+            }
+            default_dir
+        }
+    };
+    let stem = naming.file_stem(input_path, metadata);
+    Ok(dir.join(format!("{}.{}", stem, format)))
+}
+
+/// 解密主函数，根据文件头或扩展名自动识别格式。
+///
+/// 等价于使用对应选项调用 [`convert`]：`output_path` 为确切输出路径（扩展名会被替换），
+/// `skip` 为 `true` 时跳过已存在的输出。
+pub fn decrypt_and_dump(
+    input_path: &Path,
+    output_path: Option<&Path>,
+    skip: bool,
+) -> Result<PathBuf, NcmError> {
+    let mut options = ConvertOptions::new().overwrite(if skip {
+        OverwritePolicy::Skip
+    } else {
+        OverwritePolicy::Overwrite
+    });
+    if let Some(p) = output_path {
+        options = options.output_path(p);
+    }
+    Ok(convert(input_path, &mut options)?.output_path)
+}
+
+/// 按照 `options` 转换单个加密文件。
+///
+/// 被取消时会删除已写入的部分输出，并返回 [`NcmError::Cancelled`]。
+pub fn convert(input_path: &Path, options: &mut ConvertOptions) -> Result<ConvertReport, NcmError> {
+    if options.is_cancelled() {
+        return Err(NcmError::Cancelled);
+    }
+
+    let mut input_file = File::open(input_path)?;
+    let total = stream_len(&mut input_file)?;
+    options.report(Phase::Header, 0, total);
+
+    let default_registry;
+    let registry = match options.registry {
+        Some(registry) => registry,
+        None => {
+            default_registry = Registry::default();
+            &default_registry
+        }
+    };
+    let decryptor = registry.detect(&mut input_file, Some(input_path))?;
+    let OpenedTrack {
+        format,
+        version,
+        key_set,
+        metadata: meta_data,
+        raw_metadata,
+        cover: image_data,
+        warnings,
+        mut audio,
+    } = decryptor.open_with_path(Box::new(input_file), Some(input_path))?;
+    let mut warnings = warnings.into_iter();
+    if options.strict
+        && let Some(error) = warnings.next()
+    {
+        return Err(error);
+    }
+
+    let final_output_path = resolve_output_path(
+        input_path,
+        &options.target,
+        &options.naming,
+        &meta_data,
+        &format,
+    )?;
+    let mut report = ConvertReport {
+        output_path: final_output_path,
+        format,
+        version,
+        key_set,
+        bytes_written: 0,
+        tags_written: Vec::new(),
+        cover_path: None,
+        skipped: false,
+        warnings: warnings.map(|w| w.to_string()).collect(),
+    };
+    let final_output_path = &report.output_path;
+
+    if final_output_path.exists() {
+        match options.overwrite {
+            OverwritePolicy::Overwrite => {}
+            OverwritePolicy::Skip => {
+                report.skipped = true;
+                return Ok(report);
+            }
+            OverwritePolicy::Error => {
+                return Err(NcmError::OutputExists(final_output_path.clone()));
+            }
+        }
+    }
+
+    // 如果目录不存在，则创建
+    if let Some(parent) = final_output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // 写入解密后的音频数据
+    let mut output_file = File::create(final_output_path)?;
+    let mut processed = total.saturating_sub(stream_len(&mut audio)?);
+    options.report(Phase::Audio, processed, total);
+    let mut buffer = [0u8; BUFFER_SIZE];
+    loop {
+        if options.is_cancelled() {
+            drop(output_file);
+            fs::remove_file(final_output_path)?;
+            return Err(NcmError::Cancelled);
+        }
+        let bytes_read = match audio.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        output_file.write_all(&buffer[..bytes_read])?;
+        processed += bytes_read as u64;
+        options.report(Phase::Audio, processed, total);
+    }
+    drop(output_file);
+
+    if options.is_cancelled() {
+        fs::remove_file(final_output_path)?;
+        return Err(NcmError::Cancelled);
+    }
+
+    // 写入元数据标签
+    options.report(Phase::Tagging, processed, total);
+    let embedded_cover = match options.cover {
+        CoverPolicy::Embed => image_data.as_deref(),
+        CoverPolicy::Sidecar | CoverPolicy::Omit => None,
+    };
+    if !options.skip_tagging {
+        if tag::supports_format(&report.format) {
+            report.tags_written = tag::tag_path(
+                final_output_path,
+                &report.format,
+                &meta_data,
+                embedded_cover,
+            )?;
+            if options.write_163_key {
+                // 有原文时原样加密，没有时（如 .uc 缓存）由元数据生成
+                let meta_key = &KeySet::default().meta_key;
+                let comment = match &raw_metadata {
+                    Some(raw) => Some(ncm::encrypt_163_key(raw, meta_key)),
+                    None if meta_data.music_id.is_some() => {
+                        Some(ncm::encode_163_key(&meta_data, meta_key)?)
+                    }
+                    None => None,
+                };
+                match comment {
+                    Some(comment) => {
+                        tag::write_163_key(final_output_path, &report.format, &comment)?;
+                        report.tags_written.push("163 key");
+                    }
+                    None => report
+                        .warnings
+                        .push("元数据中没有歌曲 ID，未写入 163 key 注释".to_string()),
+                }
+            }
+        } else {
+            report
+                .warnings
+                .push(format!("不支持为 {} 格式写入标签", report.format));
+        }
+    } else if options.cover == CoverPolicy::Embed && image_data.is_some() {
+        report
+            .warnings
+            .push("未开启标签写入，封面没有嵌入".to_string());
+    }
+
+    if options.cover == CoverPolicy::Sidecar {
+        match image_data.as_deref() {
+            Some(img_data) => {
+                let ext = if img_data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
+                    "png"
+                } else {
+                    "jpg"
+                };
+                let cover_path = final_output_path.with_extension(ext);
+                fs::write(&cover_path, img_data)?;
+                report.cover_path = Some(cover_path);
+            }
+            None => report.warnings.push("文件中没有封面".to_string()),
+        }
+    }
+    options.report(Phase::Tagging, total, total);
+
+    report.bytes_written = fs::metadata(&report.output_path)?.len();
+    Ok(report)
+}
@@ -0,0 +1,161 @@
+use crate::kgm::KgmDecryptor;
+use crate::metadata::NcmMetadata;
+use crate::ncm::{NcmDecryptor, NcmError};
+use crate::qmc::QmcDecryptor;
+use crate::uc::UcDecryptor;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// 识别格式时从文件开头读取的字节数
+pub const SNIFF_LEN: usize = 64;
+
+/// 同时实现 `Read + Seek` 的数据流，用于在 [`Decryptor`] 中传递装箱的读取器
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// 由 [`Decryptor::open`] 解析得到的音轨
+pub struct OpenedTrack {
+    /// 音频格式（小写，如 `mp3`、`flac`），决定输出扩展名和标签写入方式
+    pub format: String,
+    /// 容器格式的版本号（如果该格式有版本号）
+    pub version: Option<String>,
+    /// 解密所用的密钥组名称（如果该格式支持多组密钥）
+    pub key_set: Option<String>,
+    pub metadata: NcmMetadata,
+    /// 解密后的元数据原文（如 `music:{...}`），只有带元数据区块的 NCM 文件才有。
+    /// 写入 `163 key` 注释时原样加密，保留客户端写入的全部字段
+    pub raw_metadata: Option<String>,
+    pub cover: Option<Vec<u8>>,
+    /// 不妨碍解密的问题（如未知的版本），由调用方决定作为警告还是错误
+    pub warnings: Vec<NcmError>,
+    /// 解密后的音频数据，位置 0 对应音频的第一个字节
+    pub audio: Box<dyn ReadSeek>,
+}
+
+/// 一种加密音乐格式的解密器
+pub trait Decryptor: Send + Sync {
+    /// 格式名称，如 `ncm`
+    fn name(&self) -> &'static str;
+
+    /// 该格式使用的文件扩展名（小写，不含点）
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// 根据文件开头最多 [`SNIFF_LEN`] 个字节判断是否为该格式，没有固定文件头的格式返回 `false`
+    fn sniff(&self, header: &[u8]) -> bool;
+
+    /// 解析元数据和封面，并返回解密后音频数据的读取器
+    fn open(&self, reader: Box<dyn ReadSeek>) -> Result<OpenedTrack, NcmError>;
+
+    /// 同 [`Decryptor::open`]，额外传入文件路径，供需要从文件名获取信息的格式使用
+    fn open_with_path(
+        &self,
+        reader: Box<dyn ReadSeek>,
+        path: Option<&Path>,
+    ) -> Result<OpenedTrack, NcmError> {
+        let _ = path;
+        self.open(reader)
+    }
+}
+
+/// 解密器注册表，按文件头或扩展名选择对应的 [`Decryptor`]
+pub struct Registry {
+    decryptors: Vec<Box<dyn Decryptor>>,
+}
+
+impl Registry {
+    /// 创建空的注册表
+    pub fn new() -> Self {
+        Self {
+            decryptors: Vec::new(),
+        }
+    }
+
+    /// 注册一个解密器，先注册的优先匹配
+    pub fn register(&mut self, decryptor: impl Decryptor + 'static) {
+        self.decryptors.push(Box::new(decryptor));
+    }
+
+    /// 替换同名的解密器，没有同名的则注册到最后
+    pub fn replace(&mut self, decryptor: impl Decryptor + 'static) {
+        match self
+            .decryptors
+            .iter_mut()
+            .find(|d| d.name() == decryptor.name())
+        {
+            Some(existing) => *existing = Box::new(decryptor),
+            None => self.register(decryptor),
+        }
+    }
+
+    /// 已注册的解密器
+    pub fn decryptors(&self) -> impl Iterator<Item = &dyn Decryptor> {
+        self.decryptors.iter().map(|d| d.as_ref())
+    }
+
+    /// 按扩展名查找解密器，不区分大小写
+    pub fn by_extension(&self, ext: &str) -> Option<&dyn Decryptor> {
+        let ext = ext.to_lowercase();
+        self.decryptors()
+            .find(|d| d.extensions().contains(&ext.as_str()))
+    }
+
+    /// 按文件头查找解密器
+    pub fn by_magic(&self, header: &[u8]) -> Option<&dyn Decryptor> {
+        self.decryptors().find(|d| d.sniff(header))
+    }
+
+    /// 路径的扩展名是否有对应的解密器
+    pub fn supports_path(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.by_extension(ext).is_some())
+    }
+
+    /// 识别数据流的格式：先匹配文件头，再按 `path` 的扩展名匹配。
+    /// 返回前会将 `reader` 定位回开头。
+    pub fn detect<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        path: Option<&Path>,
+    ) -> Result<&dyn Decryptor, NcmError> {
+        let mut header = Vec::with_capacity(SNIFF_LEN);
+        reader
+            .by_ref()
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut header)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        self.by_magic(&header)
+            .or_else(|| {
+                path.and_then(|p| p.extension())
+                    .and_then(|ext| ext.to_str())
+                    .and_then(|ext| self.by_extension(ext))
+            })
+            .ok_or_else(|| {
+                NcmError::Unsupported(
+                    path.map_or_else(|| "<未知>".to_string(), |p| p.display().to_string()),
+                )
+            })
+    }
+
+    /// 打开文件，自动识别格式并解析
+    pub fn open(&self, path: &Path) -> Result<OpenedTrack, NcmError> {
+        let mut file = File::open(path)?;
+        let decryptor = self.detect(&mut file, Some(path))?;
+        decryptor.open_with_path(Box::new(file), Some(path))
+    }
+}
+
+impl Default for Registry {
+    /// 包含所有内置格式的注册表
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(NcmDecryptor::new());
+        registry.register(UcDecryptor::new());
+        registry.register(QmcDecryptor::new());
+        registry.register(KgmDecryptor::new());
+        registry
+    }
+}
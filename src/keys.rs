@@ -0,0 +1,105 @@
+//! 解密 NCM 文件中 RC4 密钥和元数据所用的 AES 密钥组。
+
+use crate::metadata;
+use crate::ncm::NcmError;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+// 官方客户端使用的密钥
+const CORE_KEY: [u8; 16] = *b"\x68\x7a\x48\x52\x41\x6d\x73\x6f\x35\x6b\x49\x6e\x62\x61\x78\x57";
+const META_KEY: [u8; 16] = *b"\x23\x31\x34\x6c\x6a\x6b\x5f\x21\x5c\x5d\x26\x30\x55\x3c\x27\x28";
+
+/// 一组 AES-128 密钥：`core_key` 解密 RC4 密钥，`meta_key` 解密元数据。
+///
+/// 修改版或地区版客户端可能使用相同的容器和不同的密钥，解析时按顺序尝试多组密钥，
+/// 第一组能解密 RC4 密钥的即被采用。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySet {
+    pub name: String,
+    pub core_key: [u8; 16],
+    pub meta_key: [u8; 16],
+}
+
+impl Default for KeySet {
+    /// 官方客户端的密钥组
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_NAME, CORE_KEY, META_KEY)
+    }
+}
+
+/// 配置文件中的一组密钥，密钥为十六进制字符串
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeySetConfig {
+    name: String,
+    core_key: String,
+    meta_key: String,
+}
+
+impl KeySet {
+    /// 官方客户端密钥组的名称
+    pub const DEFAULT_NAME: &str = "netease";
+
+    pub fn new(name: impl Into<String>, core_key: [u8; 16], meta_key: [u8; 16]) -> Self {
+        Self {
+            name: name.into(),
+            core_key,
+            meta_key,
+        }
+    }
+
+    /// 从十六进制字符串创建，密钥必须为 16 字节
+    pub fn from_hex(
+        name: impl Into<String>,
+        core_key: &str,
+        meta_key: &str,
+    ) -> Result<Self, NcmError> {
+        let name = name.into();
+        let core_key = parse_key(&name, "coreKey", core_key)?;
+        let meta_key = parse_key(&name, "metaKey", meta_key)?;
+        Ok(Self::new(name, core_key, meta_key))
+    }
+
+    /// 解析 JSON 格式的密钥组列表：
+    ///
+    /// ```json
+    /// [{ "name": "variant", "coreKey": "687a4852...", "metaKey": "2331346c..." }]
+    /// ```
+    pub fn list_from_json(json: &str) -> Result<Vec<Self>, NcmError> {
+        let configs: Vec<KeySetConfig> = metadata::deserialize_json(json)?;
+        configs
+            .into_iter()
+            .map(|c| Self::from_hex(c.name, &c.core_key, &c.meta_key))
+            .collect()
+    }
+
+    /// 从 JSON 文件读取密钥组列表，格式见 [`KeySet::list_from_json`]
+    pub fn load_list(path: &Path) -> Result<Vec<Self>, NcmError> {
+        Self::list_from_json(&fs::read_to_string(path)?)
+    }
+}
+
+/// 在 `key_sets` 之后追加官方密钥组（如果列表中还没有），保证普通文件仍能解密
+pub fn with_default(mut key_sets: Vec<KeySet>) -> Vec<KeySet> {
+    let default = KeySet::default();
+    if !key_sets
+        .iter()
+        .any(|k| k.core_key == default.core_key && k.meta_key == default.meta_key)
+    {
+        key_sets.push(default);
+    }
+    key_sets
+}
+
+fn parse_key(name: &str, field: &str, hex_key: &str) -> Result<[u8; 16], NcmError> {
+    let bytes = hex::decode(hex_key.trim())?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        NcmError::InvalidKey(format!(
+            "密钥组 {} 的 {} 应为 16 字节，实际为 {} 字节",
+            name,
+            field,
+            bytes.len()
+        ))
+    })
+}
@@ -0,0 +1,332 @@
+//! 酷狗音乐的加密格式（.kgm、.kgma、.vpr）。
+//!
+//! 文件以固定的 16 字节魔数开头，之后是音频数据的偏移、加密版本、密钥槽位和每个文件各自的密钥。
+//! 目前支持第 3 版加密：文件密钥和槽位密钥经 MD5 变换后作为掩码表，与偏移一起异或得到音频。
+//! .vpr 文件在此基础上还要再异或一个固定序列。文件本身不含元数据，音频中原有的标签会被读出作为元数据。
+
+use crate::cipher::{CipherReader, PositionCipher};
+use crate::decryptor::{self, OpenedTrack, ReadSeek, SNIFF_LEN};
+use crate::metadata::NcmMetadata;
+use crate::ncm::{NcmError, Section};
+use crate::{sniff, tag};
+use byteorder::{ByteOrder, LittleEndian};
+use md5::{Digest, Md5};
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub(crate) const KGM_MAGIC: [u8; 16] = [
+    0x7C, 0xD5, 0x32, 0xEB, 0x86, 0x02, 0x7F, 0x4B, 0xA8, 0xAF, 0xA6, 0x8E, 0x0F, 0xFF, 0x99, 0x14,
+];
+pub(crate) const VPR_MAGIC: [u8; 16] = [
+    0x05, 0x28, 0xBC, 0x96, 0xE9, 0xE4, 0x5A, 0x43, 0x91, 0xAA, 0xBD, 0xD0, 0x7A, 0xF5, 0x36, 0x31,
+];
+// 魔数、音频偏移、加密版本、密钥槽位、校验数据和文件密钥
+const HEADER_LEN: usize = 16 + 4 + 4 + 4 + 16 + 16;
+// .vpr 文件额外异或的序列
+const VPR_MASK: [u8; 17] = [
+    0x25, 0xDF, 0xE8, 0xA6, 0x75, 0x1E, 0x75, 0x0E, 0x2F, 0x80, 0xF3, 0x2D, 0xB8, 0xB6, 0xE3, 0x11,
+    0x00,
+];
+// 第 3 版加密中各槽位对应的密钥
+const SLOT_KEYS: &[(u32, &[u8])] = &[(1, &[0x6C, 0x2C, 0x2F, 0x27])];
+
+/// KGM / VPR 文件头
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KgmHeader {
+    /// 是否为 .vpr 文件
+    pub vpr: bool,
+    /// 音频数据在文件中的偏移，即文件头的长度
+    pub audio_offset: u32,
+    pub crypto_version: u32,
+    pub crypto_slot: u32,
+    pub test_data: [u8; 16],
+    /// 每个文件各自的密钥
+    pub key: [u8; 16],
+}
+
+impl KgmHeader {
+    /// 解析文件开头的字节，魔数不符或长度不足时返回错误
+    pub fn parse(data: &[u8]) -> Result<Self, NcmError> {
+        let vpr = if data.starts_with(&KGM_MAGIC) {
+            false
+        } else if data.starts_with(&VPR_MAGIC) {
+            true
+        } else {
+            return Err(NcmError::corrupt(
+                Section::Header,
+                0,
+                "魔数不符，不是 KGM / VPR 文件",
+            ));
+        };
+        if data.len() < HEADER_LEN {
+            return Err(NcmError::corrupt(
+                Section::Header,
+                0,
+                format!("文件头过短 ({} 字节)", data.len()),
+            ));
+        }
+        let audio_offset = LittleEndian::read_u32(&data[16..20]);
+        if (audio_offset as usize) < HEADER_LEN {
+            return Err(NcmError::corrupt(
+                Section::Header,
+                16,
+                format!("音频数据偏移 {} 落在文件头内", audio_offset),
+            ));
+        }
+        Ok(Self {
+            vpr,
+            audio_offset,
+            crypto_version: LittleEndian::read_u32(&data[20..24]),
+            crypto_slot: LittleEndian::read_u32(&data[24..28]),
+            test_data: data[28..44].try_into().unwrap_or_default(),
+            key: data[44..60].try_into().unwrap_or_default(),
+        })
+    }
+}
+
+/// 第 3 版加密的掩码
+#[derive(Debug, Clone)]
+pub struct KgmCipher {
+    slot_box: [u8; 16],
+    file_box: [u8; 17],
+    vpr: bool,
+}
+
+impl KgmCipher {
+    /// 根据文件头中的加密版本、槽位和密钥创建，只支持第 3 版
+    pub fn new(header: &KgmHeader) -> Result<Self, NcmError> {
+        if header.crypto_version != 3 {
+            return Err(NcmError::Unsupported(format!(
+                "KGM 加密版本 {}，目前只支持第 3 版",
+                header.crypto_version
+            )));
+        }
+        let slot_key = SLOT_KEYS
+            .iter()
+            .find(|(slot, _)| *slot == header.crypto_slot)
+            .map(|(_, key)| *key)
+            .ok_or_else(|| {
+                NcmError::InvalidKey(format!("未知的 KGM 密钥槽位 {}", header.crypto_slot))
+            })?;
+
+        let mut file_box = [0u8; 17];
+        file_box[..16].copy_from_slice(&kugou_md5(&header.key));
+        file_box[16] = 0x6B;
+        Ok(Self {
+            slot_box: kugou_md5(slot_key),
+            file_box,
+            vpr: header.vpr,
+        })
+    }
+}
+
+impl PositionCipher for KgmCipher {
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let pos = offset + i as u64;
+            *byte ^= self.file_box[(pos % 17) as usize];
+            *byte ^= *byte << 4;
+            *byte ^= self.slot_box[(pos % 16) as usize];
+            *byte ^= (pos as u32).to_le_bytes().iter().fold(0, |acc, b| acc ^ b);
+            if self.vpr {
+                *byte ^= VPR_MASK[(pos % 17) as usize];
+            }
+        }
+    }
+}
+
+/// MD5 摘要按 2 字节为单位倒序排列
+fn kugou_md5(data: &[u8]) -> [u8; 16] {
+    let digest = Md5::digest(data);
+    let mut out = [0u8; 16];
+    for i in (0..16).step_by(2) {
+        out[i] = digest[14 - i];
+        out[i + 1] = digest[15 - i];
+    }
+    out
+}
+
+/// 解密 KGM / VPR 音频数据的读取器
+pub type KgmAudioReader<R> = CipherReader<R, KgmCipher>;
+
+impl<R: Read + Seek> KgmAudioReader<R> {
+    /// 将 `inner` 定位到音频数据开头
+    pub fn new(inner: R, cipher: KgmCipher, audio_offset: u64) -> io::Result<Self> {
+        Self::new_at(inner, cipher, audio_offset, None)
+    }
+}
+
+/// 酷狗音乐 .kgm / .vpr 文件的 [`decryptor::Decryptor`] 实现
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KgmDecryptor;
+
+impl KgmDecryptor {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl decryptor::Decryptor for KgmDecryptor {
+    fn name(&self) -> &'static str {
+        "kgm"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["kgm", "kgma", "vpr"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.starts_with(&KGM_MAGIC) || header.starts_with(&VPR_MAGIC)
+    }
+
+    fn open(&self, mut reader: Box<dyn ReadSeek>) -> Result<OpenedTrack, NcmError> {
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        reader.seek(SeekFrom::Start(0))?;
+        reader
+            .by_ref()
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)?;
+        let header = KgmHeader::parse(&head)?;
+        let cipher = KgmCipher::new(&header)?;
+
+        let mut audio = KgmAudioReader::new(reader, cipher, header.audio_offset as u64)?;
+        let container = sniff::read_container(&mut audio)?;
+        let mut warnings = Vec::new();
+        if container.is_none() {
+            warnings.push(NcmError::UnknownContainer(header.audio_offset as u64));
+        }
+
+        let format = container.unwrap_or("mp3");
+        let metadata = NcmMetadata {
+            format: Some(format.to_string()),
+            ..tag::read_metadata(&mut audio, format)
+        };
+
+        Ok(OpenedTrack {
+            format: format.to_string(),
+            version: Some(header.crypto_version.to_string()),
+            key_set: None,
+            metadata,
+            raw_metadata: None,
+            cover: None,
+            warnings,
+            audio: Box::new(audio),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: [u8; 16] = [
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E,
+        0x1F,
+    ];
+    const AUDIO_OFFSET: u32 = 1024;
+
+    fn sample_header(magic: &[u8; 16], version: u32, slot: u32) -> Vec<u8> {
+        let mut data = magic.to_vec();
+        data.extend_from_slice(&AUDIO_OFFSET.to_le_bytes());
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&slot.to_le_bytes());
+        data.extend_from_slice(&[0xEE; 16]);
+        data.extend_from_slice(&KEY);
+        data
+    }
+
+    #[test]
+    fn parse_header() {
+        let header = KgmHeader::parse(&sample_header(&KGM_MAGIC, 3, 1)).unwrap();
+        assert_eq!(
+            header,
+            KgmHeader {
+                vpr: false,
+                audio_offset: AUDIO_OFFSET,
+                crypto_version: 3,
+                crypto_slot: 1,
+                test_data: [0xEE; 16],
+                key: KEY,
+            }
+        );
+        assert!(
+            KgmHeader::parse(&sample_header(&VPR_MAGIC, 3, 1))
+                .unwrap()
+                .vpr
+        );
+
+        let corrupt_offset = |data: &[u8]| match KgmHeader::parse(data) {
+            Err(NcmError::Corrupt {
+                section: Section::Header,
+                offset,
+                ..
+            }) => offset,
+            other => panic!("应为文件头损坏，实际为 {:?}", other),
+        };
+        assert_eq!(corrupt_offset(&[0; HEADER_LEN]), 0);
+        assert_eq!(corrupt_offset(&sample_header(&KGM_MAGIC, 3, 1)[..59]), 0);
+        let mut data = sample_header(&KGM_MAGIC, 3, 1);
+        data[16..20].copy_from_slice(&59u32.to_le_bytes());
+        assert_eq!(corrupt_offset(&data), 16);
+
+        let header = KgmHeader::parse(&sample_header(&KGM_MAGIC, 2, 1)).unwrap();
+        assert!(matches!(
+            KgmCipher::new(&header),
+            Err(NcmError::Unsupported(_))
+        ));
+        let header = KgmHeader::parse(&sample_header(&KGM_MAGIC, 3, 2)).unwrap();
+        assert!(matches!(
+            KgmCipher::new(&header),
+            Err(NcmError::InvalidKey(_))
+        ));
+    }
+
+    /// 读取音频数据全为 0 的文件中 `offset` 处的 8 字节，得到的就是掩码
+    fn decrypt_zeros(magic: &[u8; 16], offsets: &[u64]) -> Vec<[u8; 8]> {
+        let mut data = sample_header(magic, 3, 1);
+        data.resize(AUDIO_OFFSET as usize + 8192, 0);
+        let header = KgmHeader::parse(&data).unwrap();
+        let cipher = KgmCipher::new(&header).unwrap();
+        let mut audio =
+            KgmAudioReader::new(Cursor::new(data), cipher, AUDIO_OFFSET as u64).unwrap();
+        offsets
+            .iter()
+            .map(|&offset| {
+                let mut buf = [0; 8];
+                audio.seek(SeekFrom::Start(offset)).unwrap();
+                audio.read_exact(&mut buf).unwrap();
+                buf
+            })
+            .collect()
+    }
+
+    // 期望值由另外按参考实现（unlock-music）编写的程序生成，
+    // 偏移分别跨过 16 / 17 字节的掩码周期和 256 字节处偏移高位的变化
+    const OFFSETS: [u64; 4] = [0, 12, 250, 4094];
+
+    #[test]
+    fn decrypt_kgm() {
+        assert_eq!(
+            decrypt_zeros(&KGM_MAGIC, &OFFSETS),
+            [
+                [0xD9, 0x94, 0xB3, 0xCE, 0x39, 0xF5, 0x66, 0xE9],
+                [0x49, 0x94, 0xC4, 0x3E, 0xDF, 0x3F, 0x74, 0x03],
+                [0x4D, 0xB8, 0xDC, 0xB4, 0x44, 0xB7, 0x63, 0x42],
+                [0x3B, 0xC1, 0xDF, 0x3F, 0x74, 0x03, 0x65, 0x1E],
+            ]
+        );
+    }
+
+    #[test]
+    fn decrypt_vpr() {
+        assert_eq!(
+            decrypt_zeros(&VPR_MAGIC, &OFFSETS),
+            [
+                [0xFC, 0x4B, 0x5B, 0x68, 0x4C, 0xEB, 0x13, 0xE7],
+                [0xF1, 0x22, 0x27, 0x2F, 0xDF, 0x1A, 0xAB, 0xEB],
+                [0xF5, 0x0E, 0x3F, 0xA5, 0x44, 0x92, 0xBC, 0xAA],
+                [0xD8, 0xD0, 0xDF, 0x1A, 0xAB, 0xEB, 0xC3, 0x6B],
+            ]
+        );
+    }
+}
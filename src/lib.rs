@@ -0,0 +1,45 @@
+//! 网易云音乐 .ncm 文件、.uc 缓存文件，以及 QQ 音乐 QMC 文件和酷狗音乐 KGM 文件解密库。
+//!
+//! 使用 [`NcmFile`] 解析文件头、元数据和封面，并按需读取解密后的音频；
+//! 使用 [`convert()`] 和 [`ConvertOptions`] 将加密文件转换为带标签的 .mp3 / .flac 文件，
+//! 支持的格式由 [`Registry`] 中注册的 [`Decryptor`] 决定；
+//! 使用 [`decrypt_bytes`] 在内存中完成解密和标签写入；
+//! 使用 [`retag()`] 为客户端直接下载的 mp3 / flac 文件恢复标签。
+//!
+//! 启用 `async` feature 后，`async_io` 模块提供基于 tokio 的异步版本。
+
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod cipher;
+pub mod convert;
+pub mod decryptor;
+pub mod keys;
+pub mod kgm;
+pub mod metadata;
+pub mod ncm;
+mod parser;
+pub mod progress;
+pub mod qmc;
+pub mod retag;
+pub mod sniff;
+pub mod tag;
+pub mod uc;
+
+pub use cipher::{CipherReader, PositionCipher};
+pub use convert::{
+    ConvertOptions, ConvertReport, CoverPolicy, NamingStrategy, OutputTarget, OverwritePolicy,
+    convert, decrypt_and_dump,
+};
+pub use decryptor::{Decryptor, OpenedTrack, Registry};
+pub use keys::KeySet;
+pub use kgm::{KgmAudioReader, KgmDecryptor, KgmHeader};
+pub use metadata::{NcmArtist, NcmDjProgram, NcmMetadata};
+pub use ncm::{
+    DecryptedTrack, NcmAudioReader, NcmDecryptor, NcmError, NcmFile, NcmHeader, NcmInfo,
+    NcmVersion, Section, decrypt_163_key, decrypt_bytes, encode_163_key, encrypt_163_key,
+};
+pub use progress::{CancellationToken, Phase, Progress};
+pub use qmc::{QmcDecryptor, QmcReader};
+pub use retag::{RetagReport, retag};
+pub use sniff::sniff_container;
+pub use uc::{CacheEntry, CacheIndex, UcDecryptor, UcReader};
@@ -1,14 +1,29 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use ncmcvt::{
+    ConvertOptions, KeySet, NamingStrategy, NcmDecryptor, NcmError, NcmMetadata, OverwritePolicy,
+    Registry, UcDecryptor, convert, keys, ncm, retag, sniff,
+};
+use std::fs::{self, File};
+use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
-mod ncm;
 
-/// 从网易云音乐的 .ncm 文件格式中解密音乐文件。
+/// 从网易云音乐的 .ncm、.uc 和 QQ 音乐的 .qmc0 / .qmcflac / .mflac、酷狗音乐的 .kgm / .vpr 等加密格式中解密音乐文件。
 /// 默认输出为同名 .mp3 / .flac 文件。
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// 一个或多个 .ncm 文件的路径
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// 一个或多个加密文件或目录的路径
     #[arg(required = true, name = "FILES")]
     files: Vec<PathBuf>,
 
@@ -19,47 +34,293 @@ struct Args {
     /// 如果输出文件已存在则跳过（如果没有指定，默认覆盖）
     #[arg(short, long)]
     skip: bool,
+
+    /// 严格模式：未知版本、封面区块异常等问题视为错误，不输出文件
+    #[arg(long)]
+    strict: bool,
+
+    /// 在输出文件中写入客户端格式的 163 key 注释，使客户端仍能识别歌曲（匹配歌词、云盘等）
+    #[arg(long)]
+    write_163_key: bool,
+
+    /// 歌曲 ID 到元数据的映射文件（JSON），用于为 .uc 缓存文件写入标签
+    #[arg(long, value_name = "FILE")]
+    uc_songs: Option<PathBuf>,
+
+    #[command(flatten)]
+    keys: KeyArgs,
+
+    /// 输出文件的命名方式
+    #[arg(long, value_enum, default_value_t = Naming::Input)]
+    naming: Naming,
+}
+
+#[derive(clap::Args, Debug)]
+struct KeyArgs {
+    /// 密钥组配置文件（JSON），按顺序尝试，最后尝试官方客户端的密钥
+    #[arg(long, value_name = "FILE")]
+    keys: Option<PathBuf>,
+
+    /// 自定义的 CORE_KEY（十六进制），优先于配置文件中的密钥组
+    #[arg(long, value_name = "HEX", requires = "meta_key")]
+    core_key: Option<String>,
+
+    /// 自定义的 META_KEY（十六进制），需与 --core-key 一起使用
+    #[arg(long, value_name = "HEX", requires = "core_key")]
+    meta_key: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 将 mp3 / flac 文件、元数据和封面打包为 .ncm 文件
+    Pack(PackArgs),
+    /// 解密客户端下载的 mp3 / flac 中的 163 key 注释，重新写入标签（直接修改文件）
+    Retag(RetagArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RetagArgs {
+    /// 一个或多个 mp3 / flac 文件或目录的路径。
+    /// 音频旁有同名的 .jpg / .png 时会作为封面嵌入
+    #[arg(required = true, name = "FILES")]
+    files: Vec<PathBuf>,
+
+    #[command(flatten)]
+    keys: KeyArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct PackArgs {
+    /// 明文音频文件（mp3 或 flac）
+    audio: PathBuf,
+
+    /// 元数据 JSON 文件，格式与 .ncm 中 `music:` 之后的内容相同
+    #[arg(short, long)]
+    meta: PathBuf,
+
+    /// 封面图片
+    #[arg(short, long)]
+    cover: Option<PathBuf>,
+
+    /// RC4 密钥（如果没有指定，随机生成）
+    #[arg(short, long)]
+    key: Option<String>,
+
+    /// 输出路径（如果没有指定，与音频文件同名，扩展名为 .ncm）
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Naming {
+    /// 与输入文件同名
+    Input,
+    /// 艺术家 - 曲名
+    ArtistTitle,
+    /// 歌曲 ID（适合恢复缓存文件）
+    SongId,
+}
+
+impl From<Naming> for NamingStrategy<'_> {
+    fn from(naming: Naming) -> Self {
+        match naming {
+            Naming::Input => NamingStrategy::InputStem,
+            Naming::ArtistTitle => NamingStrategy::ArtistTitle,
+            Naming::SongId => NamingStrategy::SongId,
+        }
+    }
 }
 
 fn main() {
     let args = Args::parse();
+    match &args.command {
+        Some(Command::Pack(pack_args)) => {
+            match pack(pack_args) {
+                Ok(output) => println!("成功打包到: \"{}\"", output.display()),
+                Err(e) => {
+                    eprintln!("打包 \"{}\" 时出错: {}", pack_args.audio.display(), e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Retag(retag_args)) => {
+            retag_files(retag_args);
+            return;
+        }
+        None => {}
+    }
+
+    let mut registry = Registry::default();
+    if let Some(key_sets) = key_sets_or_exit(&args.keys) {
+        registry.replace(NcmDecryptor::with_key_sets(key_sets));
+    }
+    if let Some(path) = &args.uc_songs {
+        match UcDecryptor::from_song_map(path) {
+            Ok(decryptor) => registry.replace(decryptor),
+            Err(e) => {
+                eprintln!("错误: 无法读取映射文件 '{}': {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut options = ConvertOptions::new().overwrite(if args.skip {
+        OverwritePolicy::Skip
+    } else {
+        OverwritePolicy::Overwrite
+    });
+    options = options
+        .strict(args.strict)
+        .write_163_key(args.write_163_key)
+        .naming(args.naming.into())
+        .registry(&registry);
+    if let Some(dir) = &args.output {
+        options = options.output_dir(dir);
+    }
 
     for path in &args.files {
         if path.is_dir() {
             // 如果是目录，则遍历目录
             for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-                if entry.path().is_file()
-                    && entry.path().extension().map_or(false, |ext| ext == "ncm")
-                {
-                    process_file(entry.path(), &args.output, args.skip);
+                if entry.path().is_file() && registry.supports_path(entry.path()) {
+                    process_file(entry.path(), &mut options);
                 }
             }
         } else if path.is_file() {
             // 如果是文件
-            process_file(path, &args.output, args.skip);
+            process_file(path, &mut options);
         } else {
             eprintln!("错误: 找不到文件或目录 '{}'", path.display());
         }
     }
 }
 
-/// 处理单个 NCM 文件。
-fn process_file(input_path: &Path, output_dir: &Option<PathBuf>, skip: bool) {
+/// 处理单个加密文件。
+fn process_file(input_path: &Path, options: &mut ConvertOptions) {
     println!("正在处理: {}", input_path.display());
 
-    let output_path = match output_dir {
-        Some(dir) => {
-            let file_name = input_path.file_stem().unwrap_or_else(|| {
-                // 如果没有文件名，则使用默认名称
-                std::ffi::OsStr::new("unnamed_file")
-            });
-            Some(dir.join(file_name))
+    match convert(input_path, options) {
+        Ok(report) if report.skipped => {
+            println!("文件已存在，跳过: {}", report.output_path.display())
+        }
+        Ok(report) => {
+            if let Some(version) = &report.version {
+                println!("格式版本: {}", version);
+            }
+            if let Some(key_set) = report
+                .key_set
+                .as_deref()
+                .filter(|&name| name != KeySet::DEFAULT_NAME)
+            {
+                println!("使用密钥组: {}", key_set);
+            }
+            for warning in &report.warnings {
+                eprintln!("警告: {}", warning);
+            }
+            println!("成功解密到: \"{}\"", report.output_path.display())
         }
-        None => None, // dump 函数将处理 None 的情况
-    };
-
-    match ncm::decrypt_and_dump(input_path, output_path.as_deref(), skip) {
-        Ok(final_path) => println!("成功解密到: \"{}\"", final_path.display()),
         Err(e) => eprintln!("处理 \"{}\" 时出错: {}", input_path.display(), e),
     }
 }
+
+/// 同 [`key_sets`]，读取失败时退出。
+fn key_sets_or_exit(args: &KeyArgs) -> Option<Vec<KeySet>> {
+    key_sets(args).unwrap_or_else(|e| {
+        eprintln!("错误: 无法读取密钥: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// 由命令行参数组成要尝试的密钥组，没有自定义密钥时返回 `None`。
+fn key_sets(args: &KeyArgs) -> Result<Option<Vec<KeySet>>, NcmError> {
+    let mut key_sets = Vec::new();
+    if let (Some(core_key), Some(meta_key)) = (&args.core_key, &args.meta_key) {
+        key_sets.push(KeySet::from_hex("命令行", core_key, meta_key)?);
+    }
+    if let Some(path) = &args.keys {
+        key_sets.extend(KeySet::load_list(path)?);
+    }
+    if key_sets.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(keys::with_default(key_sets)))
+}
+
+/// 为 mp3 / flac 文件恢复标签，目录中的文件递归处理。
+fn retag_files(args: &RetagArgs) {
+    let key_sets = key_sets_or_exit(&args.keys).unwrap_or_else(|| vec![KeySet::default()]);
+    for path in &args.files {
+        if path.is_dir() {
+            for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+                let is_audio = entry
+                    .path()
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| matches!(ext.to_lowercase().as_str(), "mp3" | "flac"));
+                if entry.path().is_file() && is_audio {
+                    retag_file(entry.path(), &key_sets);
+                }
+            }
+        } else if path.is_file() {
+            retag_file(path, &key_sets);
+        } else {
+            eprintln!("错误: 找不到文件或目录 '{}'", path.display());
+        }
+    }
+}
+
+/// 为单个文件恢复标签。
+fn retag_file(path: &Path, key_sets: &[KeySet]) {
+    println!("正在处理: {}", path.display());
+
+    match retag(path, key_sets) {
+        Ok(report) => {
+            if report.key_set != KeySet::DEFAULT_NAME {
+                println!("使用密钥组: {}", report.key_set);
+            }
+            match (&report.cover_path, &report.metadata.album_pic) {
+                (Some(cover), _) => println!("嵌入封面: {}", cover.display()),
+                (None, Some(url)) => println!("封面链接: {}", url),
+                (None, None) => {}
+            }
+            println!(
+                "成功写入标签: {} - {}",
+                report.metadata.artist_names().join("/"),
+                report.metadata.title()
+            )
+        }
+        Err(e) => eprintln!("处理 \"{}\" 时出错: {}", path.display(), e),
+    }
+}
+
+/// 打包为 .ncm 文件，返回输出路径。
+fn pack(args: &PackArgs) -> Result<PathBuf, NcmError> {
+    let mut audio = File::open(&args.audio)?;
+    let meta_text = fs::read_to_string(&args.meta)?;
+    let mut metadata = NcmMetadata::from_prefixed(&meta_text)
+        .unwrap_or_else(|| NcmMetadata::from_json(&meta_text))?;
+
+    // 元数据中没有格式时按音频内容识别
+    let container = sniff::read_container(&mut audio)?;
+    if !matches!(container, Some("mp3" | "flac")) {
+        eprintln!("警告: 无法识别为 mp3 或 flac，将按原样打包");
+    }
+    if metadata.format.is_none() {
+        metadata.format = container.map(str::to_string);
+    }
+
+    let cover = args.cover.as_ref().map(fs::read).transpose()?;
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.audio.with_extension("ncm"));
+    ncm::encode(
+        audio,
+        BufWriter::new(File::create(&output)?),
+        &metadata,
+        cover.as_deref(),
+        args.key.as_deref().map(str::as_bytes),
+    )?;
+    Ok(output)
+}
@@ -0,0 +1,372 @@
+use crate::ncm::NcmError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+/// NCM 文件中解密出的元数据（网易云客户端写入的 `music:` JSON）。
+/// 电台节目的 `dj:` JSON 会被映射到同样的字段，原始节目信息保存在 [`NcmMetadata::dj`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NcmMetadata {
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub music_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub music_name: Option<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub artist: Vec<NcmArtist>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub album_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(
+        deserialize_with = "string_or_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub album_pic_doc_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album_pic: Option<String>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub bitrate: Option<u64>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mp3_doc_id: Option<String>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mv_id: Option<u64>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub alias: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub trans_names: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub flag: Option<u64>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub track_no: Option<u64>,
+    /// 电台节目信息，仅 `dj:` 元数据有
+    #[serde(skip)]
+    pub dj: Option<Box<NcmDjProgram>>,
+    /// 未识别的字段，原样保留
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// 电台节目的元数据（网易云客户端写入的 `dj:` JSON），曲目本身的信息位于 `mainMusic`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NcmDjProgram {
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub program_id: Option<u64>,
+    /// 节目名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_name: Option<String>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dj_id: Option<u64>,
+    /// 主播昵称
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dj_name: Option<String>,
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub radio_id: Option<u64>,
+    /// 电台名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radio_name: Option<String>,
+    /// 电台品牌名，部分文件只有它而没有 `radioName`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brand: Option<String>,
+    /// 节目期数
+    #[serde(
+        deserialize_with = "id_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub serial: Option<u64>,
+    pub main_music: NcmMetadata,
+    /// 未识别的字段，原样保留
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// 艺术家，在 JSON 中以 `["名字", id]` 的形式存储，id 可能是字符串或缺失，缺失时为 0
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawArtist", into = "(String, u64)")]
+pub struct NcmArtist {
+    pub name: String,
+    pub id: u64,
+}
+
+#[derive(Deserialize)]
+struct RawArtist(
+    String,
+    #[serde(default, deserialize_with = "id_or_string")] Option<u64>,
+);
+
+impl From<RawArtist> for NcmArtist {
+    fn from(RawArtist(name, id): RawArtist) -> Self {
+        Self {
+            name,
+            id: id.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<NcmArtist> for (String, u64) {
+    fn from(artist: NcmArtist) -> Self {
+        (artist.name, artist.id)
+    }
+}
+
+impl NcmMetadata {
+    /// 从 JSON 字符串解析元数据，字段类型不符时返回出错字段的路径
+    pub fn from_json(json: &str) -> Result<Self, NcmError> {
+        deserialize_json(json)
+    }
+
+    /// 从电台节目的 JSON 字符串解析元数据。
+    ///
+    /// 节目名、电台名、主播昵称和期数分别作为曲名、专辑、艺术家和音轨号，
+    /// 格式、码率等其余字段取自 `mainMusic`
+    pub fn from_dj_json(json: &str) -> Result<Self, NcmError> {
+        let program: NcmDjProgram = deserialize_json(json)?;
+        let mut meta = program.main_music.clone();
+        if let Some(name) = &program.program_name {
+            meta.music_name = Some(name.clone());
+        }
+        if let Some(radio) = program.radio_name.as_ref().or(program.brand.as_ref()) {
+            meta.album = Some(radio.clone());
+        }
+        if let Some(dj_name) = &program.dj_name {
+            meta.artist = vec![NcmArtist {
+                name: dj_name.clone(),
+                id: program.dj_id.unwrap_or_default(),
+            }];
+        }
+        if program.serial.is_some() {
+            meta.track_no = program.serial;
+        }
+        meta.dj = Some(Box::new(program));
+        Ok(meta)
+    }
+
+    /// 解析带类型前缀的元数据，支持 `music:` 和 `dj:`，前缀未知时返回 `None`
+    pub fn from_prefixed(text: &str) -> Option<Result<Self, NcmError>> {
+        if let Some(json) = text.strip_prefix("music:") {
+            Some(Self::from_json(json))
+        } else {
+            text.strip_prefix("dj:").map(Self::from_dj_json)
+        }
+    }
+
+    /// 音频格式（小写，如 `mp3`、`flac`），缺失时默认为 `mp3`
+    pub fn format(&self) -> String {
+        self.format.as_deref().unwrap_or("mp3").to_lowercase()
+    }
+
+    /// 曲名，缺失时为 "未知曲目"
+    pub fn title(&self) -> &str {
+        self.music_name.as_deref().unwrap_or("未知曲目")
+    }
+
+    /// 专辑名，缺失时为 "未知专辑"
+    pub fn album_name(&self) -> &str {
+        self.album.as_deref().unwrap_or("未知专辑")
+    }
+
+    /// 艺术家名字列表，缺失时为 "未知艺术家"
+    pub fn artist_names(&self) -> Vec<String> {
+        if self.artist.is_empty() {
+            vec!["未知艺术家".to_string()]
+        } else {
+            self.artist.iter().map(|a| a.name.clone()).collect()
+        }
+    }
+}
+
+/// 解析 JSON，字段类型不符时返回出错字段的路径
+pub(crate) fn deserialize_json<T: DeserializeOwned>(json: &str) -> Result<T, NcmError> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    serde_path_to_error::deserialize(&mut deserializer).map_err(|e| NcmError::MetadataField {
+        path: e.path().to_string(),
+        source: e.into_inner(),
+    })
+}
+
+/// 客户端有时把 id 写成字符串，这里同时接受数字和数字字符串。
+/// 码率、时长等数字也可能写成 `320000.0` 这样的浮点数，空字符串视为缺失
+pub(crate) fn id_or_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(n)) => n
+            .as_u64()
+            .or_else(|| {
+                n.as_f64()
+                    .filter(|f| f.fract() == 0.0 && (0.0..u64::MAX as f64).contains(f))
+                    .map(|f| f as u64)
+            })
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("无效的 id: {}", n))),
+        Some(Value::String(s)) if s.is_empty() => Ok(None),
+        Some(Value::String(s)) => s
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("无效的 id: \"{}\"", s))),
+        Some(other) => Err(serde::de::Error::custom(format!("无效的 id: {}", other))),
+    }
+}
+
+/// 把 `null` 当作缺失，使用默认值
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// 同时接受字符串和数字，统一保存为字符串
+fn string_or_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(Value::Number(n)) => Ok(Some(n.to_string())),
+        Some(other) => Err(serde::de::Error::custom(format!(
+            "应为字符串或数字: {}",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lenient_fields() {
+        let meta = NcmMetadata::from_json(
+            r#"{"musicId":"1","artist":[["歌手","123"],["合唱",""],["乐队"]],
+                "mvId":"","alias":null,"transNames":null,
+                "bitrate":320000.0,"duration":"215000","trackNo":3.0}"#,
+        )
+        .unwrap();
+        assert_eq!(meta.music_id, Some(1));
+        assert_eq!(
+            meta.artist,
+            [
+                NcmArtist {
+                    name: "歌手".to_string(),
+                    id: 123
+                },
+                NcmArtist {
+                    name: "合唱".to_string(),
+                    id: 0
+                },
+                NcmArtist {
+                    name: "乐队".to_string(),
+                    id: 0
+                },
+            ]
+        );
+        assert_eq!(meta.mv_id, None);
+        assert!(meta.alias.is_empty());
+        assert!(meta.trans_names.is_empty());
+        assert_eq!(meta.bitrate, Some(320000));
+        assert_eq!(meta.duration, Some(215000));
+        assert_eq!(meta.track_no, Some(3));
+
+        let meta = NcmMetadata::from_json(r#"{"artist":null}"#).unwrap();
+        assert!(meta.artist.is_empty());
+    }
+
+    const DJ_JSON: &str = r#"{"programId":"2001","programName":"第 12 期","djId":77,
+        "djName":"主播","radioId":88,"brand":"电台品牌","serial":"12",
+        "mainMusic":{"musicId":3003,"musicName":"原曲","artist":[["歌手",1]],
+        "album":"原专辑","bitrate":128000,"format":"mp3"},"createTime":1}"#;
+
+    #[test]
+    fn dj_program() {
+        let meta = NcmMetadata::from_dj_json(DJ_JSON).unwrap();
+        assert_eq!(meta.title(), "第 12 期");
+        assert_eq!(meta.album_name(), "电台品牌");
+        assert_eq!(
+            meta.artist,
+            [NcmArtist {
+                name: "主播".to_string(),
+                id: 77
+            }]
+        );
+        assert_eq!(meta.track_no, Some(12));
+        // 其余字段取自 mainMusic
+        assert_eq!(meta.music_id, Some(3003));
+        assert_eq!(meta.bitrate, Some(128000));
+        assert_eq!(meta.format(), "mp3");
+
+        let program = meta.dj.as_deref().unwrap();
+        assert_eq!(program.program_id, Some(2001));
+        assert_eq!(program.radio_id, Some(88));
+        assert_eq!(program.main_music.music_name.as_deref(), Some("原曲"));
+        assert_eq!(program.extra["createTime"], 1);
+
+        // 有 radioName 时优先于 brand
+        let meta = NcmMetadata::from_dj_json(
+            r#"{"radioName":"电台","brand":"品牌","mainMusic":{"musicName":"原曲"}}"#,
+        )
+        .unwrap();
+        assert_eq!(meta.album_name(), "电台");
+        assert_eq!(meta.title(), "原曲");
+        assert!(meta.artist.is_empty());
+    }
+
+    #[test]
+    fn prefixed() {
+        let dj = NcmMetadata::from_prefixed(&format!("dj:{}", DJ_JSON))
+            .unwrap()
+            .unwrap();
+        assert_eq!(dj, NcmMetadata::from_dj_json(DJ_JSON).unwrap());
+
+        let music = NcmMetadata::from_prefixed(r#"music:{"musicName":"曲名"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(music.title(), "曲名");
+        assert!(music.dj.is_none());
+
+        assert!(NcmMetadata::from_prefixed(r#"{"musicName":"曲名"}"#).is_none());
+    }
+
+    #[test]
+    fn invalid_field_path() {
+        let err = NcmMetadata::from_json(r#"{"artist":[["歌手","abc"]]}"#).unwrap_err();
+        assert!(matches!(err, NcmError::MetadataField { ref path, .. } if path == "artist[0][1]"));
+        let err = NcmMetadata::from_json(r#"{"bitrate":320000.5}"#).unwrap_err();
+        assert!(matches!(err, NcmError::MetadataField { ref path, .. } if path == "bitrate"));
+    }
+}
@@ -1,27 +1,37 @@
+use crate::cipher::{CipherReader, PositionCipher};
+use crate::decryptor::{self, OpenedTrack, ReadSeek, SNIFF_LEN};
+use crate::keys::KeySet;
+use crate::metadata::NcmMetadata;
+use crate::parser::{self, Incomplete, ParsedNcm};
+use crate::{sniff, tag};
 use aes::cipher::block_padding::{Pkcs7, UnpadError};
-use aes::cipher::{BlockDecryptMut, KeyInit};
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit};
 use base64::{Engine as _, engine::general_purpose};
-use byteorder::{LittleEndian, ReadBytesExt};
 use ecb::Decryptor;
-use id3::{Tag, TagLike, Version};
-use metaflac::block::PictureType;
-use serde_json::Value;
-use std::env::home_dir;
-use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 // 定义常量
-const CORE_KEY: &[u8] = b"\x68\x7a\x48\x52\x41\x6d\x73\x6f\x35\x6b\x49\x6e\x62\x61\x78\x57";
-const META_KEY: &[u8] = b"\x23\x31\x34\x6c\x6a\x6b\x5f\x21\x5c\x5d\x26\x30\x55\x3c\x27\x28";
-const NCM_MAGIC: &[u8] = b"CTENFDAM";
-const BUFFER_SIZE: usize = 16384;
+pub(crate) const NCM_MAGIC: &[u8] = b"CTENFDAM";
+pub(crate) const BUFFER_SIZE: usize = 16384;
+// 密钥和元数据解密后的固定前缀
+const KEY_PREFIX: &[u8] = b"neteasecloudmusic";
+pub(crate) const META_PREFIX: &[u8] = b"163 key(Don't modify):";
+// 生成 NCM 时写入的版本号
+const ENCODE_VERSION: NcmVersion = NcmVersion {
+    major: 1,
+    minor: 0x70,
+};
 
 // 默认存放在 ~/ 目录下的哪个子目录
 pub const DEFAULT_DIR_UNDER_HOME: &str = "Instrumental";
 
 type EcbAes128Decrypt = Decryptor<aes::Aes128>;
+type EcbAes128Encrypt = ecb::Encryptor<aes::Aes128>;
 
 /// NCM 处理中的错误
 #[derive(Error, Debug)]
@@ -38,6 +48,11 @@ pub enum NcmError {
     Tagging(String),
     #[error("JSON 解析错误: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("元数据字段 `{path}` 格式错误: {source}")]
+    MetadataField {
+        path: String,
+        source: serde_json::Error,
+    },
     #[error("ID3 标签错误: {0}")]
     Id3(#[from] id3::Error),
     #[error("FLAC 标签错误: {0}")]
@@ -50,6 +65,85 @@ pub enum NcmError {
     FromUtf8(#[from] std::string::FromUtf8Error),
     #[error("无效的填充: {0}")]
     InvalidPadding(String),
+    #[error("不支持的文件格式: {0}")]
+    Unsupported(String),
+    #[error("{section}区块损坏 (偏移 {offset}): {reason}")]
+    Corrupt {
+        section: Section,
+        offset: u64,
+        reason: String,
+    },
+    #[error("CRC32 校验失败: 文件中记录为 {expected:08x}，实际为 {actual:08x}")]
+    Checksum { expected: u32, actual: u32 },
+    #[error("未知的 NCM 版本 {0}，已按 1.x 版本的布局解析")]
+    UnknownVersion(NcmVersion),
+    #[error("未知的 NCM 版本 {version}，按 1.x 版本的布局解析失败: {source}")]
+    UnsupportedVersion {
+        version: NcmVersion,
+        source: Box<NcmError>,
+    },
+    #[error("元数据中的格式为 {claimed}，但音频数据实际为 {actual}")]
+    FormatMismatch { claimed: String, actual: String },
+    #[error("缓存文件不完整: 缺少 {missing} 字节 (完整大小 {expected} 字节)")]
+    IncompleteCache { missing: u64, expected: u64 },
+    #[error("输出文件已存在: {}", .0.display())]
+    OutputExists(PathBuf),
+    #[error("转换已取消")]
+    Cancelled,
+    #[error("生成 NCM 失败: {0}")]
+    Encode(String),
+    #[error("无效的密钥: {0}")]
+    InvalidKey(String),
+    #[error(
+        "封面区块长度异常 (image_space {image_space}, image_size {image_size})，已按音频文件头将音频起始位置定为 {audio_offset}"
+    )]
+    CoverFrame {
+        image_space: u32,
+        image_size: u32,
+        audio_offset: u64,
+    },
+    #[error("无法从音频数据开头 (偏移 {0}) 识别出已知的容器格式，输出可能是噪音")]
+    UnknownContainer(u64),
+    #[error("文件中没有嵌入解密密钥 (文件末尾为 {0})，需要从 QQ 音乐客户端的数据库获取 ekey")]
+    KeyNotEmbedded(String),
+}
+
+impl NcmError {
+    pub(crate) fn corrupt(section: Section, offset: u64, reason: impl Into<String>) -> Self {
+        NcmError::Corrupt {
+            section,
+            offset,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// NCM 文件中的区块，用于指明解析失败的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// 文件头（魔数和版本）
+    Header,
+    /// 加密的 RC4 密钥
+    Key,
+    /// 加密的元数据
+    Metadata,
+    /// CRC 校验、封面图片及其后的空白
+    Cover,
+    /// 加密的音频数据
+    Audio,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Section::Header => "文件头",
+            Section::Key => "密钥",
+            Section::Metadata => "元数据",
+            Section::Cover => "封面",
+            Section::Audio => "音频",
+        };
+        f.write_str(name)
+    }
 }
 
 // 手动实现 From<UnpadError> 因为它没有实现 std::error::Error
@@ -60,7 +154,7 @@ impl From<UnpadError> for NcmError {
 }
 
 /// 生成 RC4 密钥流 (根据 Python 源码的逻辑)
-fn generate_rc4_keystream(key_data: &[u8]) -> Vec<u8> {
+pub(crate) fn generate_rc4_keystream(key_data: &[u8]) -> Vec<u8> {
     let key_length = key_data.len();
     let mut s_box = (0u8..=255).collect::<Vec<u8>>();
     let mut j: u8 = 0;
@@ -97,201 +191,966 @@ fn generate_rc4_keystream(key_data: &[u8]) -> Vec<u8> {
     final_stream
 }
 
-/// 从 NCM 文件中读取元数据和封面
-fn read_ncm_file(file: &mut File) -> Result<(Vec<u8>, Value, Option<Vec<u8>>), NcmError> {
-    // 验证文件头
-    let mut magic = [0u8; 8];
-    file.read_exact(&mut magic)?;
-    if magic != NCM_MAGIC {
-        return Err(NcmError::Format("无效的 NCM 文件头".to_string()));
+/// NCM 文件头中魔数之后的两个版本字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NcmVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl NcmVersion {
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            major: bytes[0],
+            minor: bytes[1],
+        }
+    }
+
+    /// 是否为已知布局的版本。目前只见过主版本号为 1 的文件，各子版本布局相同
+    pub fn is_known(&self) -> bool {
+        self.major == 1
+    }
+
+    /// 未知版本按已知布局解析失败时，在错误中注明版本号
+    pub(crate) fn wrap_error(self, error: NcmError) -> NcmError {
+        if self.is_known() {
+            error
+        } else {
+            NcmError::UnsupportedVersion {
+                version: self,
+                source: Box::new(error),
+            }
+        }
+    }
+}
+
+impl fmt::Display for NcmVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
     }
+}
 
-    file.seek(SeekFrom::Current(2))?;
+/// NCM 文件头中解析出的密钥与各区块位置
+#[derive(Debug, Clone)]
+pub struct NcmHeader {
+    /// 文件格式版本
+    pub version: NcmVersion,
+    /// 解密后的 RC4 密钥（已去掉 `neteasecloudmusic` 前缀）
+    pub key: Vec<u8>,
+    /// 成功解密的密钥组名称，见 [`KeySet`]
+    pub key_set: String,
+    /// 加密元数据区块的长度
+    pub meta_len: u32,
+    /// 文件中记录的封面图片 CRC32，没有封面时为 0
+    pub crc32: u32,
+    /// 封面区块占用的空间
+    pub image_space: u32,
+    /// 封面图片的实际大小
+    pub image_size: u32,
+    /// 封面区块（图片数据）在文件中的起始偏移
+    pub cover_offset: u64,
+    /// 加密音频数据在文件中的起始偏移
+    pub audio_offset: u64,
+}
+
+impl NcmHeader {
+    /// 用封面图片数据校验 CRC32，不一致时返回 [`NcmError::Checksum`]。
+    ///
+    /// 这里假定 CRC32 只覆盖封面图片本身（即 image_size 字节，不含 image_space 中其余的填充），
+    /// 没有封面时为 0。这一假定与 [`encode`] 的写法一致，但还没有用官方客户端生成的文件核实过，
+    /// 因此转换时不会检查，校验失败也不说明文件损坏；需要时由调用者自行调用
+    pub fn verify_crc(&self, cover: Option<&[u8]>) -> Result<(), NcmError> {
+        let actual = crc32fast::hash(cover.unwrap_or_default());
+        if actual != self.crc32 {
+            return Err(NcmError::Checksum {
+                expected: self.crc32,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 打开 NCM 文件时一次性读取的文件头、元数据、封面和容器格式。
+///
+/// [`NcmFile`] 和异步版本的 `AsyncNcmFile` 都通过 [`Deref`] 提供这里的方法
+pub struct NcmInfo {
+    pub(crate) header: NcmHeader,
+    pub(crate) metadata: NcmMetadata,
+    pub(crate) raw_metadata: Option<String>,
+    pub(crate) images: Vec<Vec<u8>>,
+    pub(crate) cipher: NcmCipher,
+    pub(crate) container: Option<&'static str>,
+}
+
+/// 已解析的 NCM 文件。
+///
+/// 文件头、元数据和封面在打开时一次性读取，见 [`NcmInfo`]；音频数据则通过
+/// [`NcmFile::audio`] 按需解密，不会写入磁盘。
+/// 数据来源可以是任意 `Read + Seek`，如 [`File`]、[`Cursor`] 或归档中的条目。
+pub struct NcmFile<R = File> {
+    reader: R,
+    info: NcmInfo,
+}
+
+impl NcmFile<File> {
+    /// 打开并解析指定路径的 NCM 文件
+    pub fn open(path: &Path) -> Result<Self, NcmError> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    /// 打开并解析指定路径的 NCM 文件，依次尝试 `key_sets` 中的密钥组
+    pub fn open_with_keys(path: &Path, key_sets: &[KeySet]) -> Result<Self, NcmError> {
+        Self::from_reader_with_keys(File::open(path)?, key_sets)
+    }
+}
+
+impl<R: Read + Seek> NcmFile<R> {
+    /// 从数据流解析 NCM，游标需位于 NCM 数据开头
+    pub fn from_reader(reader: R) -> Result<Self, NcmError> {
+        Self::from_reader_with_keys(reader, &[KeySet::default()])
+    }
+
+    /// 从数据流解析 NCM，依次尝试 `key_sets` 中的密钥组
+    pub fn from_reader_with_keys(mut reader: R, key_sets: &[KeySet]) -> Result<Self, NcmError> {
+        let parsed = read_ncm_file(&mut reader, key_sets)?;
+        let cipher = NcmCipher::new(&parsed.header.key);
+        let container = sniff::read_container(&mut NcmAudioReader::new_at(
+            &mut reader,
+            cipher.clone(),
+            parsed.header.audio_offset,
+            None,
+        )?)?;
+        Ok(Self {
+            reader,
+            info: NcmInfo::new(parsed, cipher, container),
+        })
+    }
+
+    /// 返回解密后音频数据的读取器，支持 `Read + Seek`
+    pub fn audio(&mut self) -> Result<NcmAudioReader<&mut R>, NcmError> {
+        Ok(NcmAudioReader::new_at(
+            &mut self.reader,
+            self.info.cipher.clone(),
+            self.info.header.audio_offset,
+            None,
+        )?)
+    }
+
+    /// 消耗自身，返回解密后音频数据的读取器，支持 `Read + Seek`
+    pub fn into_audio(self) -> Result<NcmAudioReader<R>, NcmError> {
+        Ok(NcmAudioReader::new_at(
+            self.reader,
+            self.info.cipher,
+            self.info.header.audio_offset,
+            None,
+        )?)
+    }
+}
+
+impl<R> Deref for NcmFile<R> {
+    type Target = NcmInfo;
+
+    fn deref(&self) -> &NcmInfo {
+        &self.info
+    }
+}
+
+impl NcmInfo {
+    pub(crate) fn new(
+        parsed: ParsedNcm,
+        cipher: NcmCipher,
+        container: Option<&'static str>,
+    ) -> Self {
+        let ParsedNcm {
+            header,
+            metadata,
+            raw_metadata,
+            images,
+        } = parsed;
+        Self {
+            header,
+            metadata,
+            raw_metadata,
+            images,
+            cipher,
+            container,
+        }
+    }
 
-    // 解密核心密钥
-    let key_len = file.read_u32::<LittleEndian>()? as usize;
-    let mut key_data = vec![0u8; key_len];
-    file.read_exact(&mut key_data)?;
+    /// 文件头信息
+    pub fn header(&self) -> &NcmHeader {
+        &self.header
+    }
+
+    /// 解密后的元数据
+    pub fn metadata(&self) -> &NcmMetadata {
+        &self.metadata
+    }
+
+    /// 解密后的元数据原文（如 `music:{...}`），包含 [`NcmMetadata`] 之外的字段，没有元数据区块时为 `None`
+    pub fn raw_metadata(&self) -> Option<&str> {
+        self.raw_metadata.as_deref()
+    }
+
+    /// 封面图片数据（如果有）
+    pub fn cover(&self) -> Option<&[u8]> {
+        self.images.first().map(Vec::as_slice)
+    }
+
+    /// 封面区块中的所有图片，第一张为封面
+    pub fn images(&self) -> &[Vec<u8>] {
+        &self.images
+    }
+
+    /// 音频格式（小写，如 `mp3`、`flac`）。
+    /// 优先使用从音频数据识别出的容器格式，无法识别时使用元数据中的格式，都没有时默认为 `mp3`
+    pub fn format(&self) -> String {
+        self.container
+            .map_or_else(|| self.metadata.format(), str::to_string)
+    }
+
+    /// 从解密后音频数据开头识别出的容器格式
+    pub fn container(&self) -> Option<&'static str> {
+        self.container
+    }
+
+    /// 校验文件中记录的 CRC32，见 [`NcmHeader::verify_crc`]
+    pub fn verify_crc(&self) -> Result<(), NcmError> {
+        self.header.verify_crc(self.cover())
+    }
+
+    /// 检查封面区块的长度和音频起始位置，见 [`NcmError::CoverFrame`]
+    pub fn verify_cover_frame(&self) -> Result<(), NcmError> {
+        check_cover_frame(&self.header)
+    }
+
+    /// 检查元数据中的格式与识别出的容器格式是否一致，不一致时返回 [`NcmError::FormatMismatch`]
+    pub fn verify_format(&self) -> Result<(), NcmError> {
+        check_format(&self.header, &self.metadata, self.container)
+    }
+
+    /// 转换时报告的警告：未知版本、封面区块异常、无法识别容器格式和格式不一致
+    pub(crate) fn warnings(&self) -> Vec<NcmError> {
+        let mut warnings = Vec::new();
+        let version = self.header.version;
+        if !version.is_known() {
+            warnings.push(NcmError::UnknownVersion(version));
+        }
+        warnings.extend(self.verify_cover_frame().err());
+        if self.container.is_none() {
+            warnings.push(NcmError::UnknownContainer(self.header.audio_offset));
+        }
+        warnings.extend(self.verify_format().err());
+        warnings
+    }
+}
+
+/// 没有元数据时格式是按文件大小猜测的，不做比较
+pub(crate) fn check_format(
+    header: &NcmHeader,
+    metadata: &NcmMetadata,
+    container: Option<&'static str>,
+) -> Result<(), NcmError> {
+    if let (Some(actual), true) = (container, header.meta_len > 0) {
+        let claimed = metadata.format();
+        if claimed != actual {
+            return Err(NcmError::FormatMismatch {
+                claimed,
+                actual: actual.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// NCM 音频数据的加密：由 RC4 密钥生成的密钥流按音频内的绝对偏移循环使用
+#[derive(Debug, Clone)]
+pub struct NcmCipher {
+    key_stream: Vec<u8>,
+}
+
+impl NcmCipher {
+    /// `key` 为去掉前缀后的 RC4 密钥，不能为空
+    pub(crate) fn new(key: &[u8]) -> Self {
+        Self {
+            key_stream: generate_rc4_keystream(key),
+        }
+    }
+}
+
+impl PositionCipher for NcmCipher {
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        apply_keystream(&self.key_stream, offset, buf);
+    }
+}
+
+/// 解密音频数据的读取器。
+///
+/// 包装整个 NCM 数据流，对外只暴露音频部分：位置 0 对应音频数据的第一个字节。
+/// 密钥流按音频内的绝对偏移计算，因此可以任意定位后再读取。
+pub type NcmAudioReader<R> = CipherReader<R, NcmCipher>;
+
+impl<R: Read + Seek> NcmAudioReader<R> {
+    /// 根据文件头创建读取器，并将 `inner` 定位到音频数据开头
+    pub fn new(inner: R, header: &NcmHeader) -> io::Result<Self> {
+        if header.key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "RC4 密钥为空"));
+        }
+        Self::new_at(
+            inner,
+            NcmCipher::new(&header.key),
+            header.audio_offset,
+            None,
+        )
+    }
+}
+
+/// 用密钥流解密 `buf`，`offset` 为 `buf` 首字节在音频数据中的绝对偏移
+pub(crate) fn apply_keystream(key_stream: &[u8], offset: u64, buf: &mut [u8]) {
+    let period = key_stream.len() as u64;
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte ^= key_stream[((offset + i as u64) % period) as usize];
+    }
+}
+
+/// 通过定位获取数据流的总长度，不改变当前位置
+pub(crate) fn stream_len<R: Seek>(reader: &mut R) -> io::Result<u64> {
+    let pos = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(pos))?;
+    Ok(len)
+}
+
+/// 依次尝试 `key_sets` 解密文件头中的 RC4 密钥，返回去掉 `neteasecloudmusic` 前缀后的密钥
+/// 和成功的密钥组。`offset` 为密钥区块在文件中的偏移，仅用于错误信息。
+pub(crate) fn decrypt_key<'k>(
+    key_data: &mut [u8],
+    offset: u64,
+    key_sets: &'k [KeySet],
+) -> Result<(Vec<u8>, &'k KeySet), NcmError> {
     key_data.iter_mut().for_each(|byte| *byte ^= 0x64);
 
-    let core_cipher = EcbAes128Decrypt::new(CORE_KEY.into());
-    let decrypted_key = core_cipher.decrypt_padded_vec_mut::<Pkcs7>(&mut key_data)?;
+    let mut errors = Vec::new();
+    for key_set in key_sets {
+        match decrypt_key_with(key_data, offset, &key_set.core_key) {
+            Ok(key) => return Ok((key, key_set)),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.len() == 1 {
+        return Err(errors.remove(0));
+    }
+    let names: Vec<&str> = key_sets.iter().map(|k| k.name.as_str()).collect();
+    Err(NcmError::corrupt(
+        Section::Key,
+        offset,
+        format!("所有密钥组都无法解密 (尝试了 {})", names.join(", ")),
+    ))
+}
+
+fn decrypt_key_with(
+    key_data: &[u8],
+    offset: u64,
+    core_key: &[u8; 16],
+) -> Result<Vec<u8>, NcmError> {
+    let core_cipher = EcbAes128Decrypt::new(core_key.into());
+    let decrypted_key = core_cipher
+        .decrypt_padded_vec_mut::<Pkcs7>(key_data)
+        .map_err(|_| NcmError::corrupt(Section::Key, offset, "AES 解密后填充无效"))?;
+
+    // 错误的密钥偶尔也能得到有效的填充，用固定前缀确认
+    if !decrypted_key.starts_with(KEY_PREFIX) {
+        return Err(NcmError::corrupt(
+            Section::Key,
+            offset,
+            "解密后缺少 `neteasecloudmusic` 前缀",
+        ));
+    }
+    match decrypted_key.get(KEY_PREFIX.len()..) {
+        Some(key) if !key.is_empty() => Ok(key.to_vec()),
+        _ => Err(NcmError::corrupt(
+            Section::Key,
+            offset,
+            format!("解密后的密钥过短 ({} 字节)", decrypted_key.len()),
+        )),
+    }
+}
 
-    let key_stream = generate_rc4_keystream(&decrypted_key[17..]);
+/// 解密元数据区块，返回元数据和解密后的原文（如 `music:{...}`）。
+/// `offset` 为元数据区块在文件中的偏移，仅用于错误信息
+pub(crate) fn decrypt_metadata(
+    meta_encrypted: &mut [u8],
+    offset: u64,
+    meta_key: &[u8; 16],
+) -> Result<(NcmMetadata, String), NcmError> {
+    meta_encrypted.iter_mut().for_each(|byte| *byte ^= 0x63);
+    decrypt_meta_comment(meta_encrypted, offset, meta_key)
+}
 
-    // 解密元数据
-    let meta_len = file.read_u32::<LittleEndian>()? as usize;
-    let meta_data = if meta_len > 0 {
-        let mut meta_encrypted = vec![0u8; meta_len];
-        file.read_exact(&mut meta_encrypted)?;
-        meta_encrypted.iter_mut().for_each(|byte| *byte ^= 0x63);
+/// 解密 `163 key(Don't modify):` 开头的元数据注释，即元数据区块异或 `0x63` 之后的内容
+fn decrypt_meta_comment(
+    comment: &[u8],
+    offset: u64,
+    meta_key: &[u8; 16],
+) -> Result<(NcmMetadata, String), NcmError> {
+    let b64_data = comment.get(META_PREFIX.len()..).ok_or_else(|| {
+        NcmError::corrupt(
+            Section::Metadata,
+            offset,
+            format!("元数据过短 ({} 字节)", comment.len()),
+        )
+    })?;
+    let b64_decoded = general_purpose::STANDARD.decode(b64_data).map_err(|e| {
+        NcmError::corrupt(Section::Metadata, offset, format!("Base64 解码失败: {}", e))
+    })?;
 
-        let mut b64_decoded = general_purpose::STANDARD.decode(&meta_encrypted[22..])?;
+    let meta_cipher = EcbAes128Decrypt::new(meta_key.into());
+    let decrypted_meta = meta_cipher
+        .decrypt_padded_vec_mut::<Pkcs7>(&b64_decoded)
+        .map_err(|_| NcmError::corrupt(Section::Metadata, offset, "AES 解密后填充无效"))?;
 
-        let meta_cipher = EcbAes128Decrypt::new(META_KEY.into());
-        let decrypted_meta = meta_cipher.decrypt_padded_vec_mut::<Pkcs7>(&mut b64_decoded)?;
+    let json_str = String::from_utf8(decrypted_meta).map_err(|e| {
+        NcmError::corrupt(
+            Section::Metadata,
+            offset,
+            format!("不是有效的 UTF-8: {}", e),
+        )
+    })?;
+    let metadata = NcmMetadata::from_prefixed(&json_str).unwrap_or_else(|| {
+        let prefix: String = json_str
+            .chars()
+            .take_while(|&c| c != ':')
+            .take(16)
+            .collect();
+        Err(NcmError::corrupt(
+            Section::Metadata,
+            offset,
+            format!("未知的元数据类型 `{}`，应为 `music:` 或 `dj:`", prefix),
+        ))
+    })?;
+    Ok((metadata, json_str))
+}
 
-        let json_str = String::from_utf8(decrypted_meta.to_vec())?;
-        serde_json::from_str(&json_str[6..])?
-    } else {
-        // 如果没有元数据，根据文件大小猜测格式
-        let file_size = file.metadata()?.len();
-        let format = if file_size > 1024 * 1024 * 16 {
-            "flac"
-        } else {
-            "mp3"
-        };
-        serde_json::json!({ "format": format })
+/// 解密网易云客户端下载的 mp3 / flac 中 `163 key(Don't modify):` 开头的注释，
+/// 依次尝试 `key_sets` 的 `meta_key`，返回元数据和成功的密钥组
+pub fn decrypt_163_key<'k>(
+    comment: &str,
+    key_sets: &'k [KeySet],
+) -> Result<(NcmMetadata, &'k KeySet), NcmError> {
+    let comment = comment.trim();
+    if !comment.as_bytes().starts_with(META_PREFIX) {
+        return Err(NcmError::Metadata(
+            "注释不是以 `163 key(Don't modify):` 开头".to_string(),
+        ));
+    }
+
+    let mut reasons = Vec::new();
+    for key_set in key_sets {
+        match decrypt_meta_comment(comment.as_bytes(), 0, &key_set.meta_key) {
+            Ok((metadata, _)) => return Ok((metadata, key_set)),
+            Err(NcmError::Corrupt { reason, .. }) => reasons.push(reason),
+            Err(e) => reasons.push(e.to_string()),
+        }
+    }
+    let reason = match reasons.as_slice() {
+        [reason] => reason.clone(),
+        _ => {
+            let names: Vec<&str> = key_sets.iter().map(|k| k.name.as_str()).collect();
+            format!("所有密钥组都无法解密 (尝试了 {})", names.join(", "))
+        }
     };
+    Err(NcmError::Metadata(format!(
+        "无法解密 163 key 注释: {}",
+        reason
+    )))
+}
 
-    // 读取封面图片
-    file.seek(SeekFrom::Current(5))?;
-    let image_space = file.read_u32::<LittleEndian>()? as usize;
-    let image_size = file.read_u32::<LittleEndian>()? as usize;
-    let image_data = if image_size > 0 {
-        let mut img_buf = vec![0u8; image_size];
-        file.read_exact(&mut img_buf)?;
-        Some(img_buf)
-    } else {
-        None
+/// 生成随机的 RC4 密钥（64 个字母和数字）
+fn random_key() -> Result<Vec<u8>, NcmError> {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut key = vec![0u8; 64];
+    getrandom::fill(&mut key).map_err(|e| NcmError::Encode(format!("无法生成随机密钥: {}", e)))?;
+    key.iter_mut()
+        .for_each(|byte| *byte = ALPHABET[*byte as usize % ALPHABET.len()]);
+    Ok(key)
+}
+
+/// [`decrypt_key`] 的逆过程
+fn encrypt_key(key: &[u8], core_key: &[u8; 16]) -> Vec<u8> {
+    let mut plain = KEY_PREFIX.to_vec();
+    plain.extend_from_slice(key);
+    let mut encrypted =
+        EcbAes128Encrypt::new(core_key.into()).encrypt_padded_vec_mut::<Pkcs7>(&plain);
+    encrypted.iter_mut().for_each(|byte| *byte ^= 0x64);
+    encrypted
+}
+
+/// [`decrypt_metadata`] 的逆过程
+fn encrypt_metadata(metadata: &NcmMetadata, meta_key: &[u8; 16]) -> Result<Vec<u8>, NcmError> {
+    let mut meta = encode_163_key(metadata, meta_key)?.into_bytes();
+    meta.iter_mut().for_each(|byte| *byte ^= 0x63);
+    Ok(meta)
+}
+
+/// 由元数据生成 `163 key(Don't modify):` 开头的元数据注释，[`decrypt_163_key`] 的逆过程。
+///
+/// 电台节目写为 `dj:`，其余写为 `music:`。客户端下载的 mp3 / flac 中带有这条注释，
+/// 写入转换后的文件可以让客户端继续识别歌曲（匹配歌词、云盘等）。
+/// 只包含 [`NcmMetadata`] 中的字段，有原文时应使用 [`encrypt_163_key`]
+pub fn encode_163_key(metadata: &NcmMetadata, meta_key: &[u8; 16]) -> Result<String, NcmError> {
+    let json = match &metadata.dj {
+        Some(program) => format!("dj:{}", serde_json::to_string(program)?),
+        None => format!("music:{}", serde_json::to_string(metadata)?),
     };
+    Ok(encrypt_163_key(&json, meta_key))
+}
 
-    // **修正**: 跳过图片数据和实际音频数据之间的空白区域
-    if image_space > image_size {
-        file.seek(SeekFrom::Current((image_space - image_size) as i64))?;
-    }
-
-    Ok((key_stream, meta_data, image_data))
-}
-
-/// NCM 文件解密主函数
-pub fn decrypt_and_dump(
-    input_path: &Path,
-    output_path: Option<&Path>,
-    skip: bool,
-) -> Result<PathBuf, NcmError> {
-    let mut input_file = File::open(input_path)?;
-
-    let (key_stream, meta_data, image_data) = read_ncm_file(&mut input_file)?;
-
-    let format = meta_data["format"].as_str().unwrap_or("mp3").to_lowercase();
-
-    let final_output_path = match output_path {
-        Some(p) => p.with_extension(&format),
-        None => {
-            let home_dir = home_dir().ok_or_else(|| {
-                NcmError::FileIo(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "无法获取用户的 ~ 目录",
-                ))
-            })?;
-            let default_dir = home_dir.join(DEFAULT_DIR_UNDER_HOME);
-            if !default_dir.exists() {
-                return Err(NcmError::FileIo(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("目录不存在: {}", default_dir.display()),
-                )));
-            }
-            default_dir
-                .join(input_path.file_name().unwrap())
-                .with_extension(&format)
-        }
+/// 原样加密元数据原文（如 [`NcmInfo::raw_metadata`]），生成 `163 key(Don't modify):` 开头的注释
+pub fn encrypt_163_key(raw: &str, meta_key: &[u8; 16]) -> String {
+    let encrypted =
+        EcbAes128Encrypt::new(meta_key.into()).encrypt_padded_vec_mut::<Pkcs7>(raw.as_bytes());
+    format!(
+        "{}{}",
+        String::from_utf8_lossy(META_PREFIX),
+        general_purpose::STANDARD.encode(encrypted)
+    )
+}
+
+/// 由明文音频、元数据和封面生成 NCM 文件并写入 `output`，返回写入的文件头信息。
+///
+/// `key` 为 RC4 密钥，为 `None` 时随机生成；使用官方客户端的密钥组加密。
+/// 生成的文件可以被 [`NcmFile`] 原样读回，因此音频不能为空，否则返回 [`NcmError::Encode`]。
+pub fn encode<R: Read, W: Write>(
+    mut audio: R,
+    mut output: W,
+    metadata: &NcmMetadata,
+    cover: Option<&[u8]>,
+    key: Option<&[u8]>,
+) -> Result<NcmHeader, NcmError> {
+    let key = match key {
+        Some([]) => return Err(NcmError::Encode("RC4 密钥不能为空".to_string())),
+        Some(key) => key.to_vec(),
+        None => random_key()?,
     };
+    // 先读取第一块音频，音频为空的文件无法读回
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let mut bytes_read = read_chunk(&mut audio, &mut buffer)?;
+    if bytes_read == 0 {
+        return Err(NcmError::Encode("音频数据为空".to_string()));
+    }
 
-    if skip && final_output_path.exists() {
-        println!("文件已存在，跳过: {}", final_output_path.display());
-        return Ok(final_output_path);
+    let key_set = KeySet::default();
+    let key_data = encrypt_key(&key, &key_set.core_key);
+    let meta_data = encrypt_metadata(metadata, &key_set.meta_key)?;
+    let cover = cover.unwrap_or_default();
+    let section_len = |data: &[u8]| {
+        u32::try_from(data.len()).map_err(|_| NcmError::Encode("区块超过 4 GiB".to_string()))
+    };
+
+    // 魔数、版本、两个长度字段、CRC32 与空白、image_space 与 image_size
+    let cover_offset =
+        (NCM_MAGIC.len() + 2 + 4 + key_data.len() + 4 + meta_data.len() + 5 + 8) as u64;
+    let header = NcmHeader {
+        version: ENCODE_VERSION,
+        key,
+        key_set: key_set.name,
+        meta_len: section_len(&meta_data)?,
+        crc32: crc32fast::hash(cover),
+        image_space: section_len(cover)?,
+        image_size: section_len(cover)?,
+        cover_offset,
+        audio_offset: cover_offset + cover.len() as u64,
+    };
+
+    output.write_all(NCM_MAGIC)?;
+    output.write_all(&[header.version.major, header.version.minor])?;
+    output.write_all(&section_len(&key_data)?.to_le_bytes())?;
+    output.write_all(&key_data)?;
+    output.write_all(&header.meta_len.to_le_bytes())?;
+    output.write_all(&meta_data)?;
+    // CRC32 之后的一个字节用途未知，写 0
+    output.write_all(&header.crc32.to_le_bytes())?;
+    output.write_all(&[0])?;
+    output.write_all(&header.image_space.to_le_bytes())?;
+    output.write_all(&header.image_size.to_le_bytes())?;
+    output.write_all(cover)?;
+
+    let key_stream = generate_rc4_keystream(&header.key);
+    let mut offset = 0;
+    while bytes_read > 0 {
+        apply_keystream(&key_stream, offset, &mut buffer[..bytes_read]);
+        output.write_all(&buffer[..bytes_read])?;
+        offset += bytes_read as u64;
+        bytes_read = read_chunk(&mut audio, &mut buffer)?;
+    }
+    output.flush()?;
+
+    Ok(header)
+}
+
+/// 读取一块数据，遇到 [`io::ErrorKind::Interrupted`] 时重试，返回 0 表示已读完
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
     }
+}
 
-    // 如果目录不存在，则创建
-    if let Some(parent) = final_output_path.parent() {
-        fs::create_dir_all(parent)?;
+/// 封面区块长度异常，或音频起始位置不是按 image_space 得到的，返回 [`NcmError::CoverFrame`]
+pub(crate) fn check_cover_frame(header: &NcmHeader) -> Result<(), NcmError> {
+    let expected = header.cover_offset + header.image_space.max(header.image_size) as u64;
+    if header.image_size > header.image_space || header.audio_offset != expected {
+        return Err(NcmError::CoverFrame {
+            image_space: header.image_space,
+            image_size: header.image_size,
+            audio_offset: header.audio_offset,
+        });
     }
+    Ok(())
+}
 
-    // 写入解密后的音频数据
-    let mut output_file = File::create(&final_output_path)?;
-    let mut buffer = [0u8; BUFFER_SIZE];
+/// 从 NCM 数据流中读取文件头、元数据和封面区块中的图片，解析见 [`parser`]
+fn read_ncm_file<R: Read + Seek>(file: &mut R, key_sets: &[KeySet]) -> Result<ParsedNcm, NcmError> {
+    let mut file_len = stream_len(file)?;
+    let base = file.stream_position()?;
+    let mut data = Vec::new();
+
+    let preamble = parse_with(file, &mut data, base, &mut file_len, |data, file_len| {
+        parser::parse_preamble(data, base, file_len, key_sets)
+    })?;
+
+    let mut heads = Vec::new();
+    for candidate in preamble.audio_offset_candidates(file_len) {
+        file.seek(SeekFrom::Start(candidate))?;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        file.by_ref()
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)?;
+        heads.push((candidate, head));
+    }
+    let audio_offset = preamble.select_audio_offset(&heads)?;
+
+    parse_with(file, &mut data, base, &mut file_len, |data, file_len| {
+        preamble.finish(data, base, file_len, audio_offset)
+    })
+}
+/// 反复调用 `parse`，数据不足时从文件中补充到 `data` 末尾。`data` 为文件中 `base` 开始的数据，
+/// 文件比 `file_len` 短时更新为实际长度，此时 `parse` 会返回对应区块的 [`NcmError::Corrupt`]
+fn parse_with<R: Read + Seek, T>(
+    file: &mut R,
+    data: &mut Vec<u8>,
+    base: u64,
+    file_len: &mut u64,
+    mut parse: impl FnMut(&[u8], u64) -> Result<T, Incomplete>,
+) -> Result<T, NcmError> {
     loop {
-        let bytes_read = input_file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+        match parse(data, *file_len) {
+            Ok(value) => return Ok(value),
+            Err(Incomplete::NeedMore(end)) => {
+                let have = base + data.len() as u64;
+                let target = parser::read_target(have, end, *file_len);
+                file.seek(SeekFrom::Start(have))?;
+                file.by_ref().take(target - have).read_to_end(data)?;
+                if base + (data.len() as u64) < target {
+                    *file_len = base + data.len() as u64;
+                }
+            }
+            Err(Incomplete::Failed(e)) => return Err(e),
         }
-        let data = &buffer[..bytes_read];
-        let decrypted_data: Vec<u8> = data
-            .iter()
-            .zip(key_stream.iter().cycle())
-            .map(|(d, k)| d ^ k)
-            .collect();
-        output_file.write_all(&decrypted_data)?;
-    }
-
-    // 写入元数据标签
-    let title = meta_data["musicName"].as_str().unwrap_or("未知曲目");
-    let album = meta_data["album"].as_str().unwrap_or("未知专辑");
-    let artists: Vec<String> = meta_data["artist"]
-        .as_array()
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_array())
-                .filter_map(|inner_arr| inner_arr[0].as_str())
-                .map(String::from)
-                .collect()
+    }
+}
+
+/// 网易云音乐 .ncm 格式的 [`decryptor::Decryptor`] 实现
+#[derive(Debug, Clone)]
+pub struct NcmDecryptor {
+    key_sets: Vec<KeySet>,
+}
+
+impl NcmDecryptor {
+    /// 只使用官方客户端的密钥组
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 依次尝试 `key_sets` 中的密钥组
+    pub fn with_key_sets(key_sets: Vec<KeySet>) -> Self {
+        Self { key_sets }
+    }
+}
+
+impl Default for NcmDecryptor {
+    fn default() -> Self {
+        Self::with_key_sets(vec![KeySet::default()])
+    }
+}
+
+impl decryptor::Decryptor for NcmDecryptor {
+    fn name(&self) -> &'static str {
+        "ncm"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ncm"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.starts_with(NCM_MAGIC)
+    }
+
+    fn open(&self, reader: Box<dyn ReadSeek>) -> Result<OpenedTrack, NcmError> {
+        let ncm = NcmFile::from_reader_with_keys(reader, &self.key_sets)?;
+        let format = ncm.format();
+        let metadata = ncm.metadata.clone();
+        let raw_metadata = ncm.raw_metadata.clone();
+        let cover = ncm.cover().map(<[u8]>::to_vec);
+        let warnings = ncm.warnings();
+        Ok(OpenedTrack {
+            format,
+            version: Some(ncm.header.version.to_string()),
+            key_set: Some(ncm.header.key_set.clone()),
+            metadata,
+            raw_metadata,
+            cover,
+            warnings,
+            audio: Box::new(ncm.into_audio()?),
         })
-        .unwrap_or_else(|| vec!["未知艺术家".to_string()]);
+    }
+}
 
-    let track_no = meta_data["trackNo"].as_u64();
+/// 在内存中解密得到的音频
+#[derive(Debug, Clone)]
+pub struct DecryptedTrack {
+    /// 已写入标签的音频数据
+    pub audio: Vec<u8>,
+    /// 音频格式（小写，如 `mp3`、`flac`）
+    pub format: String,
+    pub metadata: NcmMetadata,
+    pub cover: Option<Vec<u8>>,
+}
+
+/// 在内存中解密整个 NCM 文件并写入标签，不访问磁盘
+pub fn decrypt_bytes(data: &[u8]) -> Result<DecryptedTrack, NcmError> {
+    let mut ncm = NcmFile::from_reader(Cursor::new(data))?;
+    let audio_len = data.len().saturating_sub(ncm.header.audio_offset as usize);
+    let mut audio = Vec::with_capacity(audio_len);
+    ncm.audio()?.read_to_end(&mut audio)?;
 
-    if format == "mp3" {
-        // **修正**: 尝试读取现有标签，如果不存在则创建新的。
-        // 这样可以保留解密后的音频流中已有的标签。
-        let mut tag = Tag::read_from_path(&final_output_path).unwrap_or_else(|_| Tag::new());
+    let format = ncm.format();
+    let NcmInfo {
+        metadata, images, ..
+    } = ncm.info;
+    let cover = images.into_iter().next();
+    let audio = tag::tag_bytes(audio, &format, &metadata, cover.as_deref())?;
+
+    Ok(DecryptedTrack {
+        audio,
+        format,
+        metadata,
+        cover,
+    })
+}
 
-        tag.set_title(title);
-        tag.set_album(album);
-        tag.set_artist(artists.join("/"));
-        if let Some(tn) = track_no {
-            tag.set_track(tn as u32);
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::decryptor::Decryptor;
+    use crate::metadata::NcmArtist;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    const KEY: &[u8] = b"0123456789abcdef";
+    pub(crate) const COVER: &[u8] = &[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 1, 2, 3, 4];
+
+    pub(crate) fn sample_audio() -> Vec<u8> {
+        let mut audio = b"ID3\x03\x00\x00\x00\x00\x00\x00".to_vec();
+        audio.extend((0..40_000u32).map(|i| (i * 7 % 251) as u8));
+        audio
+    }
+
+    pub(crate) fn sample_metadata() -> NcmMetadata {
+        NcmMetadata {
+            music_id: Some(1234),
+            music_name: Some("曲名".to_string()),
+            artist: vec![NcmArtist {
+                name: "歌手".to_string(),
+                id: 56,
+            }],
+            album: Some("专辑".to_string()),
+            format: Some("mp3".to_string()),
+            ..Default::default()
         }
+    }
 
-        if let Some(img_data) = image_data {
-            let mime_type = if img_data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
-                "image/png"
-            } else {
-                "image/jpeg"
-            };
-            let picture = id3::frame::Picture {
-                mime_type: mime_type.to_string(),
-                picture_type: id3::frame::PictureType::CoverFront,
-                description: "Cover".to_string(),
-                data: img_data,
-            };
-            // 移除旧封面，以防重复
-            tag.remove_picture_by_type(id3::frame::PictureType::CoverFront);
-            tag.add_frame(picture);
+    pub(crate) fn encode_sample(cover: Option<&[u8]>) -> Vec<u8> {
+        let mut ncm = Vec::new();
+        encode(
+            Cursor::new(sample_audio()),
+            &mut ncm,
+            &sample_metadata(),
+            cover,
+            Some(KEY),
+        )
+        .unwrap();
+        ncm
+    }
+
+    /// 每次只读取几个字节的读取器，长度在 1 到 7 之间变化，模拟网络流等短读
+    struct ShortReader<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: Read> Read for ShortReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            let len = buf.len().min(self.reads % 7 + 1);
+            self.inner.read(&mut buf[..len])
         }
-        tag.write_to_path(&final_output_path, Version::Id3v23)?;
-    } else if format == "flac" {
-        let mut tag = metaflac::Tag::read_from_path(&final_output_path)?;
-        let comments = tag.vorbis_comments_mut();
-        comments.set_title(vec![title]);
-        comments.set_album(vec![album]);
-        comments.set_artist(artists);
-        if let Some(tn) = track_no {
-            comments.set("TRACKNUMBER", vec![tn.to_string()]);
+    }
+
+    impl<R: Seek> Seek for ShortReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn short_reads() {
+        let reader = ShortReader {
+            inner: Cursor::new(encode_sample(Some(COVER))),
+            reads: 0,
+        };
+        let mut ncm = NcmFile::from_reader(reader).unwrap();
+        assert_eq!(ncm.metadata(), &sample_metadata());
+        assert_eq!(ncm.cover(), Some(COVER));
+
+        let mut audio = Vec::new();
+        ncm.audio().unwrap().read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, sample_audio());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for cover in [None, Some(COVER)] {
+            let mut ncm = NcmFile::from_reader(Cursor::new(encode_sample(cover))).unwrap();
+            assert_eq!(ncm.metadata(), &sample_metadata());
+            assert_eq!(ncm.cover(), cover);
+            assert_eq!(ncm.header().key, KEY);
+            ncm.verify_crc().unwrap();
+            ncm.verify_cover_frame().unwrap();
+            ncm.verify_format().unwrap();
+
+            let mut audio = Vec::new();
+            ncm.audio().unwrap().read_to_end(&mut audio).unwrap();
+            assert_eq!(audio, sample_audio());
         }
+    }
 
-        if let Some(img_data) = image_data {
-            let mime_type = if img_data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
-                "image/png"
+    #[test]
+    fn crc_mismatch_is_not_a_warning() {
+        let mut data = encode_sample(Some(COVER));
+        let header = NcmFile::from_reader(Cursor::new(&data))
+            .unwrap()
+            .info
+            .header;
+        let crc_offset = header.cover_offset as usize - 13;
+        data[crc_offset] ^= 0xFF;
+
+        let ncm = NcmFile::from_reader(Cursor::new(&data)).unwrap();
+        assert!(matches!(
+            ncm.verify_crc(),
+            Err(NcmError::Checksum { expected, .. }) if expected == header.crc32 ^ 0xFF
+        ));
+        let track = NcmDecryptor::new()
+            .open(Box::new(Cursor::new(data)))
+            .unwrap();
+        assert!(track.warnings.is_empty(), "{:?}", track.warnings);
+    }
+
+    /// 解析 `data`，返回 [`NcmError::Corrupt`] 中的区块和偏移
+    fn corrupt_section(data: &[u8]) -> (Section, u64) {
+        match NcmFile::from_reader(Cursor::new(data)) {
+            Err(NcmError::Corrupt {
+                section, offset, ..
+            }) => (section, offset),
+            Err(e) => panic!("应为 Corrupt，实际为 {:?}", e),
+            Ok(_) => panic!("应为 Corrupt，实际解析成功"),
+        }
+    }
+
+    #[test]
+    fn truncated_prefixes() {
+        let ncm = encode_sample(Some(COVER));
+        let header = NcmFile::from_reader(Cursor::new(&ncm)).unwrap().info.header;
+        let key_end = 14 + LittleEndian::read_u32(&ncm[10..14]) as usize;
+        let meta_end = key_end + 4 + header.meta_len as usize;
+        let audio_offset = header.audio_offset as usize;
+
+        for len in 0..=audio_offset + SNIFF_LEN {
+            let prefix = &ncm[..len];
+            if len > audio_offset {
+                NcmFile::from_reader(Cursor::new(prefix)).unwrap();
+                continue;
+            }
+            let expected = if len < 10 {
+                Section::Header
+            } else if len < key_end {
+                Section::Key
+            } else if len < meta_end {
+                Section::Metadata
+            } else if len < header.cover_offset as usize {
+                Section::Cover
             } else {
-                "image/jpeg"
+                // 封面区块完整，但之后没有音频数据
+                assert_eq!(
+                    corrupt_section(prefix),
+                    (Section::Audio, audio_offset as u64)
+                );
+                continue;
             };
-            // 移除旧封面
-            tag.remove_picture_type(PictureType::CoverFront);
-            tag.add_picture(mime_type, PictureType::CoverFront, img_data);
+            assert_eq!(corrupt_section(prefix).0, expected, "前 {} 字节", len);
         }
-        tag.write_to_path(&final_output_path)?;
     }
 
-    Ok(final_output_path)
+    #[test]
+    fn empty_key_and_short_metadata() {
+        let ncm = encode_sample(None);
+        let key_end = 14 + LittleEndian::read_u32(&ncm[10..14]) as usize;
+
+        // 解密后只有 `neteasecloudmusic` 前缀的密钥
+        let key_data = encrypt_key(b"", &KeySet::default().core_key);
+        let mut data = ncm[..10].to_vec();
+        data.extend_from_slice(&(key_data.len() as u32).to_le_bytes());
+        data.extend_from_slice(&key_data);
+        data.extend_from_slice(&ncm[key_end..]);
+        assert_eq!(corrupt_section(&data), (Section::Key, 14));
+
+        // 比 `163 key(Don't modify):` 前缀还短的元数据
+        let meta: Vec<u8> = b"163 key".iter().map(|byte| byte ^ 0x63).collect();
+        let mut data = ncm[..key_end].to_vec();
+        data.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        data.extend_from_slice(&meta);
+        data.extend_from_slice(&[0; 13]);
+        data.extend_from_slice(&sample_audio());
+        assert_eq!(
+            corrupt_section(&data),
+            (Section::Metadata, key_end as u64 + 4)
+        );
+    }
+
+    #[test]
+    fn encode_rejects_empty_audio() {
+        let mut ncm = Vec::new();
+        let result = encode(
+            Cursor::new(Vec::new()),
+            &mut ncm,
+            &sample_metadata(),
+            None,
+            None,
+        );
+        assert!(matches!(result, Err(NcmError::Encode(_))));
+        assert!(ncm.is_empty());
+    }
 }
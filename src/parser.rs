@@ -0,0 +1,311 @@
+//! NCM 文件头的解析，只处理已经读入内存的数据，不涉及 IO。
+//!
+//! 同步的 [`crate::NcmFile`] 和异步的 `async_io::AsyncNcmFile` 共用这里的逻辑，各自只负责读取：
+//! 1. 调用 [`parse_preamble`]，返回 [`Incomplete::NeedMore`] 时补充数据后重试，得到封面区块之前的各区块；
+//! 2. 读取 [`Preamble::audio_offset_candidates`] 各位置开头的 [`SNIFF_LEN`] 字节，
+//!    由 [`Preamble::select_audio_offset`] 确定音频起始位置；
+//! 3. 调用 [`Preamble::finish`]，同样按需补充数据，得到完整的文件头、元数据和封面区块中的图片。
+//!
+//! 所有偏移都是在文件中的绝对位置，已读入的数据从 `base` 开始。
+
+use crate::decryptor::SNIFF_LEN;
+use crate::keys::KeySet;
+use crate::metadata::NcmMetadata;
+use crate::ncm::{self, NCM_MAGIC, NcmError, NcmHeader, NcmVersion, Section};
+use crate::sniff;
+use byteorder::{ByteOrder, LittleEndian};
+
+// 每次补充数据时至少多读的长度，通常一次就能覆盖封面区块之前的全部数据
+const READ_AHEAD: u64 = 4096;
+
+/// 解析没有完成的原因
+#[derive(Debug)]
+pub(crate) enum Incomplete {
+    /// 需要读入截至该偏移（不含）的数据后重新解析
+    NeedMore(u64),
+    /// 文件损坏或无法解密
+    Failed(NcmError),
+}
+
+impl From<NcmError> for Incomplete {
+    fn from(error: NcmError) -> Self {
+        Incomplete::Failed(error)
+    }
+}
+
+impl Incomplete {
+    fn wrap_error(self, version: NcmVersion) -> Self {
+        match self {
+            Incomplete::Failed(e) => Incomplete::Failed(version.wrap_error(e)),
+            need => need,
+        }
+    }
+}
+
+/// 需要读入截至 `end` 的数据、已读入截至 `have` 的数据时，这次应读到的位置
+pub(crate) fn read_target(have: u64, end: u64, file_len: u64) -> u64 {
+    end.max(have.saturating_add(READ_AHEAD)).min(file_len)
+}
+
+/// 已读入内存的一段文件数据
+struct Bytes<'a> {
+    data: &'a [u8],
+    base: u64,
+    pos: usize,
+    file_len: u64,
+}
+
+impl<'a> Bytes<'a> {
+    fn offset(&self) -> u64 {
+        self.base + self.pos as u64
+    }
+
+    /// 取出 `len` 字节，超出文件末尾时返回 [`NcmError::Corrupt`]
+    fn take(&mut self, section: Section, len: u64) -> Result<&'a [u8], Incomplete> {
+        let offset = self.offset();
+        check_section_len(section, offset, len, self.file_len)?;
+        let rest = &self.data[self.pos..];
+        if len > rest.len() as u64 {
+            return Err(Incomplete::NeedMore(offset + len));
+        }
+        self.pos += len as usize;
+        Ok(&rest[..len as usize])
+    }
+
+    fn take_u32(&mut self, section: Section) -> Result<u32, Incomplete> {
+        Ok(LittleEndian::read_u32(self.take(section, 4)?))
+    }
+}
+
+/// 解析得到的完整文件头、元数据和封面区块中的图片
+pub(crate) struct ParsedNcm {
+    pub(crate) header: NcmHeader,
+    pub(crate) metadata: NcmMetadata,
+    /// 解密后的元数据原文（如 `music:{...}`），没有元数据区块时为 `None`
+    pub(crate) raw_metadata: Option<String>,
+    pub(crate) images: Vec<Vec<u8>>,
+}
+
+/// 封面区块之前的各区块
+pub(crate) struct Preamble {
+    version: NcmVersion,
+    key: Vec<u8>,
+    key_set: String,
+    meta_len: u32,
+    metadata: NcmMetadata,
+    raw_metadata: Option<String>,
+    crc32: u32,
+    image_space: u32,
+    image_size: u32,
+    cover_offset: u64,
+}
+
+/// 从 `data`（文件中 `base` 开始的数据）解析封面区块之前的各区块，依次尝试 `key_sets` 中的密钥组
+pub(crate) fn parse_preamble(
+    data: &[u8],
+    base: u64,
+    file_len: u64,
+    key_sets: &[KeySet],
+) -> Result<Preamble, Incomplete> {
+    let mut bytes = Bytes {
+        data,
+        base,
+        pos: 0,
+        file_len,
+    };
+
+    // 验证文件头
+    if bytes.take(Section::Header, 8)? != NCM_MAGIC {
+        return Err(NcmError::Format("无效的 NCM 文件头".to_string()).into());
+    }
+
+    let version = NcmVersion::from_bytes(bytes.take(Section::Header, 2)?);
+    parse_body(&mut bytes, version, key_sets).map_err(|e| e.wrap_error(version))
+}
+
+/// 按 1.x 版本的布局解析密钥之后、封面区块之前的各区块
+fn parse_body(
+    bytes: &mut Bytes<'_>,
+    version: NcmVersion,
+    key_sets: &[KeySet],
+) -> Result<Preamble, Incomplete> {
+    // 解密核心密钥
+    let key_len = bytes.take_u32(Section::Key)? as u64;
+    let key_offset = bytes.offset();
+    let mut key_data = bytes.take(Section::Key, key_len)?.to_vec();
+    let (key, key_set) = ncm::decrypt_key(&mut key_data, key_offset, key_sets)?;
+
+    // 解密元数据
+    let meta_len = bytes.take_u32(Section::Metadata)?;
+    let (metadata, raw_metadata) = if meta_len > 0 {
+        let meta_offset = bytes.offset();
+        let mut meta_encrypted = bytes.take(Section::Metadata, meta_len as u64)?.to_vec();
+        let (metadata, raw) =
+            ncm::decrypt_metadata(&mut meta_encrypted, meta_offset, &key_set.meta_key)?;
+        (metadata, Some(raw))
+    } else {
+        (metadata_from_size(bytes.file_len), None)
+    };
+
+    // CRC32 和封面图片的长度，中间隔 1 字节
+    let crc32 = bytes.take_u32(Section::Cover)?;
+    bytes.take(Section::Cover, 1)?;
+    let image_space = bytes.take_u32(Section::Cover)?;
+    let image_size = bytes.take_u32(Section::Cover)?;
+
+    Ok(Preamble {
+        version,
+        key,
+        key_set: key_set.name.clone(),
+        meta_len,
+        metadata,
+        raw_metadata,
+        crc32,
+        image_space,
+        image_size,
+        cover_offset: bytes.offset(),
+    })
+}
+
+impl Preamble {
+    /// 音频起始位置的候选，按优先级排列，只包含文件范围内的位置：
+    /// 两个长度中较大的一个（通常 image_space 不小于 image_size），然后分别是 image_space 和 image_size
+    pub(crate) fn audio_offset_candidates(&self, file_len: u64) -> Vec<u64> {
+        let mut candidates = Vec::with_capacity(3);
+        for len in [
+            self.image_space.max(self.image_size),
+            self.image_space,
+            self.image_size,
+        ] {
+            let candidate = self.cover_offset + len as u64;
+            if candidate < file_len && !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        candidates
+    }
+
+    /// image_space 和 image_size 可能不一致，用解密后的音频文件头确定音频起始位置。
+    ///
+    /// `heads` 为各候选位置及其开头最多 [`SNIFF_LEN`] 字节的密文，选择第一个能识别出容器格式的候选，
+    /// 都不能识别时选择第一个候选
+    pub(crate) fn select_audio_offset(&self, heads: &[(u64, Vec<u8>)]) -> Result<u64, NcmError> {
+        let key_stream = ncm::generate_rc4_keystream(&self.key);
+        let is_audio = |head: &[u8]| {
+            let mut head = head[..head.len().min(SNIFF_LEN)].to_vec();
+            ncm::apply_keystream(&key_stream, 0, &mut head);
+            sniff::sniff_container(&head).is_some()
+        };
+        heads
+            .iter()
+            .find(|(_, head)| is_audio(head))
+            .or(heads.first())
+            .map(|&(offset, _)| offset)
+            .ok_or_else(|| self.version.wrap_error(self.no_audio_error()))
+    }
+
+    /// 偏移为按较大的长度计算的音频起始位置
+    fn no_audio_error(&self) -> NcmError {
+        NcmError::corrupt(
+            Section::Audio,
+            self.cover_offset + self.image_space.max(self.image_size) as u64,
+            format!(
+                "按 image_space ({}) 和 image_size ({}) 计算，封面之后都没有音频数据",
+                self.image_space, self.image_size
+            ),
+        )
+    }
+
+    /// 从 `data`（文件中 `base` 开始的数据）取出封面区块，得到完整的文件头、元数据和封面区块中的图片
+    pub(crate) fn finish(
+        &self,
+        data: &[u8],
+        base: u64,
+        file_len: u64,
+        audio_offset: u64,
+    ) -> Result<ParsedNcm, Incomplete> {
+        let mut bytes = Bytes {
+            data,
+            base,
+            pos: (self.cover_offset - base) as usize,
+            file_len,
+        };
+        // 封面区块为封面图片与音频之间的全部数据，可能包含多张图片
+        let frame = bytes
+            .take(Section::Cover, audio_offset - self.cover_offset)
+            .map_err(|e| e.wrap_error(self.version))?;
+        let images = split_cover_frame(frame, self.image_size);
+
+        let header = NcmHeader {
+            version: self.version,
+            key: self.key.clone(),
+            key_set: self.key_set.clone(),
+            meta_len: self.meta_len,
+            crc32: self.crc32,
+            image_space: self.image_space,
+            image_size: self.image_size,
+            cover_offset: self.cover_offset,
+            audio_offset,
+        };
+        Ok(ParsedNcm {
+            header,
+            metadata: self.metadata.clone(),
+            raw_metadata: self.raw_metadata.clone(),
+            images,
+        })
+    }
+}
+
+/// 如果没有元数据，根据文件大小猜测格式
+fn metadata_from_size(file_size: u64) -> NcmMetadata {
+    let format = if file_size > 1024 * 1024 * 16 {
+        "flac"
+    } else {
+        "mp3"
+    };
+    NcmMetadata {
+        format: Some(format.to_string()),
+        ..Default::default()
+    }
+}
+
+/// 检查从 `offset` 开始的 `len` 字节是否超出文件末尾
+fn check_section_len(
+    section: Section,
+    offset: u64,
+    len: u64,
+    file_len: u64,
+) -> Result<(), NcmError> {
+    if offset.saturating_add(len) > file_len {
+        return Err(NcmError::corrupt(
+            section,
+            offset,
+            format!(
+                "需要 {} 字节，但文件只剩 {} 字节",
+                len,
+                file_len.saturating_sub(offset)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// 拆分封面区块中的图片：先是 image_size 字节的封面，新版客户端可能在其后的空白中再放一张图片。
+/// image_size 为 0 时，区块开头如果是图片也会被当作封面
+fn split_cover_frame(frame: &[u8], image_size: u32) -> Vec<Vec<u8>> {
+    let (first, rest) = frame.split_at((image_size as usize).min(frame.len()));
+    let mut images = Vec::new();
+    if !first.is_empty() {
+        images.push(first.to_vec());
+    }
+    if is_image(rest) {
+        // 去掉末尾的填充，JPEG 和 PNG 都不以 0 结尾
+        let end = rest.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        images.push(rest[..end].to_vec());
+    }
+    images
+}
+
+fn is_image(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0xD8, 0xFF]) || data.starts_with(&[0x89, 0x50, 0x4E, 0x47])
+}
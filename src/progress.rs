@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 转换所处的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// 解析文件头、元数据和封面
+    Header,
+    /// 解密音频数据
+    Audio,
+    /// 写入音频标签
+    Tagging,
+}
+
+/// 一次进度回调的内容，`processed` 和 `total` 均以输入文件的字节数计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub processed: u64,
+    pub total: u64,
+}
+
+/// 取消令牌，克隆后可在其他线程中调用 [`CancellationToken::cancel`]
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 请求取消，正在进行的转换会在处理下一块数据前停止
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
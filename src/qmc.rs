@@ -0,0 +1,651 @@
+//! QQ 音乐的 QMC 加密格式。
+//!
+//! - v1（.qmc0、.qmc3、.qmcflac、.qmcogg）：原始音频的每个字节与一个只取决于偏移的掩码异或，
+//!   掩码来自固定的 256 字节表，不需要密钥。
+//! - v2（.mflac、.mgg 等）：文件末尾附有加密的密钥（ekey），经 base64 和 TEA 解密后，
+//!   按密钥长度选择映射表（不超过 300 字节）或 RC4 变体解密音频。
+//!   末尾为 `STag` 或 `musicex` 的文件没有嵌入密钥，无法离线解密。
+//!
+//! 文件没有固定文件头，实际格式由扩展名决定，解密后再按音频数据开头确认。
+//! 两个版本都不含额外的元数据，音频中原有的标签会被读出作为元数据。
+
+use crate::cipher::{CipherReader, PositionCipher};
+use crate::decryptor::{self, OpenedTrack, ReadSeek};
+use crate::metadata::NcmMetadata;
+use crate::ncm::{NcmError, stream_len};
+use crate::{sniff, tag};
+use base64::{Engine as _, engine::general_purpose};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+// 扩展名、实际格式以及是否为 v2（末尾附有密钥）
+const EXTENSION_FORMATS: &[(&str, &str, bool)] = &[
+    ("qmc0", "mp3", false),
+    ("qmc3", "mp3", false),
+    ("qmcflac", "flac", false),
+    ("qmcogg", "ogg", false),
+    ("mflac", "flac", true),
+    ("mflac0", "flac", true),
+    ("mgg", "ogg", true),
+    ("mgg0", "ogg", true),
+    ("mgg1", "ogg", true),
+    ("mggl", "ogg", true),
+];
+
+// v1 的掩码表
+const STATIC_BOX: [u8; 256] = [
+    0x77, 0x48, 0x32, 0x73, 0xDE, 0xF2, 0xC0, 0xC8, 0x95, 0xEC, 0x30, 0xB2, 0x51, 0xC3, 0xE1, 0xA0,
+    0x9E, 0xE6, 0x9D, 0xCF, 0xFA, 0x7F, 0x14, 0xD1, 0xCE, 0xB8, 0xDC, 0xC3, 0x4A, 0x67, 0x93, 0xD6,
+    0x28, 0xC2, 0x91, 0x70, 0xCA, 0x8D, 0xA2, 0xA4, 0xF0, 0x08, 0x61, 0x90, 0x7E, 0x6F, 0xA2, 0xE0,
+    0xEB, 0xAE, 0x3E, 0xB6, 0x67, 0xC7, 0x92, 0xF4, 0x91, 0xB5, 0xF6, 0x6C, 0x5E, 0x84, 0x40, 0xF7,
+    0xF3, 0x1B, 0x02, 0x7F, 0xD5, 0xAB, 0x41, 0x89, 0x28, 0xF4, 0x25, 0xCC, 0x52, 0x11, 0xAD, 0x43,
+    0x68, 0xA6, 0x41, 0x8B, 0x84, 0xB5, 0xFF, 0x2C, 0x92, 0x4A, 0x26, 0xD8, 0x47, 0x6A, 0x7C, 0x95,
+    0x61, 0xCC, 0xE6, 0xCB, 0xBB, 0x3F, 0x47, 0x58, 0x89, 0x75, 0xC3, 0x75, 0xA1, 0xD9, 0xAF, 0xCC,
+    0x08, 0x73, 0x17, 0xDC, 0xAA, 0x9A, 0xA2, 0x16, 0x41, 0xD8, 0xA2, 0x06, 0xC6, 0x8B, 0xFC, 0x66,
+    0x34, 0x9F, 0xCF, 0x18, 0x23, 0xA0, 0x0A, 0x74, 0xE7, 0x2B, 0x27, 0x70, 0x92, 0xE9, 0xAF, 0x37,
+    0xE6, 0x8C, 0xA7, 0xBC, 0x62, 0x65, 0x9C, 0xC2, 0x08, 0xC9, 0x88, 0xB3, 0xF3, 0x43, 0xAC, 0x74,
+    0x2C, 0x0F, 0xD4, 0xAF, 0xA1, 0xC3, 0x01, 0x64, 0x95, 0x4E, 0x48, 0x9F, 0xF4, 0x35, 0x78, 0x95,
+    0x7A, 0x39, 0xD6, 0x6A, 0xA0, 0x6D, 0x40, 0xE8, 0x4F, 0xA8, 0xEF, 0x11, 0x1D, 0xF3, 0x1B, 0x3F,
+    0x3F, 0x07, 0xDD, 0x6F, 0x5B, 0x19, 0x30, 0x19, 0xFB, 0xEF, 0x0E, 0x37, 0xF0, 0x0E, 0xCD, 0x16,
+    0x49, 0xFE, 0x53, 0x47, 0x13, 0x1A, 0xBD, 0xA4, 0xF1, 0x40, 0x19, 0x60, 0x0E, 0xED, 0x68, 0x09,
+    0x06, 0x5F, 0x4D, 0xCF, 0x3D, 0x1A, 0xFE, 0x20, 0x77, 0xE4, 0xD9, 0xDA, 0xF9, 0xA4, 0x2B, 0x76,
+    0x1C, 0x71, 0xDB, 0x00, 0xBC, 0xFD, 0x0C, 0x6C, 0xA5, 0x47, 0xF7, 0xF6, 0x00, 0x79, 0x4A, 0x11,
+];
+
+// 解密 ekey 的固定参数
+const EKEY_V2_PREFIX: &[u8] = b"QQMusic EncV2,Key:";
+const EKEY_V2_TEA_KEY1: [u8; 16] = *b"386ZJY!@#*$%^&)(";
+const EKEY_V2_TEA_KEY2: [u8; 16] = *b"**#!(#$%&^a1cZ,T";
+const TEA_ROUNDS: u32 = 16;
+const TEA_DELTA: u32 = 0x9E37_79B9;
+const TEA_SALT_LEN: usize = 2;
+const TEA_ZERO_LEN: usize = 7;
+
+// 密钥长度超过该值时使用 RC4 变体，否则使用映射表
+const MAP_KEY_MAX_LEN: usize = 300;
+const RC4_FIRST_SEGMENT_SIZE: u64 = 128;
+const RC4_SEGMENT_SIZE: u64 = 5120;
+
+/// QMC 音频的解密算法，由密钥长度决定
+#[derive(Debug, Clone, Default)]
+pub struct QmcCipher(CipherKind);
+
+#[derive(Debug, Clone, Default)]
+enum CipherKind {
+    /// v1 的固定掩码表
+    #[default]
+    Static,
+    /// 以密钥为掩码表，密钥不超过 300 字节
+    Map(Vec<u8>),
+    /// 分段重置的 RC4 变体，密钥超过 300 字节
+    Rc4(Rc4Cipher),
+}
+
+impl QmcCipher {
+    /// 按解密后的密钥长度选择算法，密钥为空时使用 v1 的固定掩码表
+    pub fn from_key(key: &[u8]) -> Self {
+        if key.is_empty() {
+            Self::default()
+        } else if key.len() <= MAP_KEY_MAX_LEN {
+            Self(CipherKind::Map(key.to_vec()))
+        } else {
+            Self(CipherKind::Rc4(Rc4Cipher::new(key)))
+        }
+    }
+
+    /// 算法名称，用于显示
+    pub fn name(&self) -> &'static str {
+        match self.0 {
+            CipherKind::Static => "static",
+            CipherKind::Map(_) => "map",
+            CipherKind::Rc4(_) => "rc4",
+        }
+    }
+}
+
+impl PositionCipher for QmcCipher {
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        match &self.0 {
+            CipherKind::Static => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte ^= static_mask(offset + i as u64);
+                }
+            }
+            CipherKind::Map(key) => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte ^= map_mask(key, offset + i as u64);
+                }
+            }
+            CipherKind::Rc4(rc4) => rc4.decrypt(offset, buf),
+        }
+    }
+}
+
+/// 偏移 `offset` 处的掩码
+fn static_mask(offset: u64) -> u8 {
+    let offset = if offset > 0x7FFF {
+        offset % 0x7FFF
+    } else {
+        offset
+    };
+    STATIC_BOX[((offset * offset + 27) & 0xFF) as usize]
+}
+
+fn map_mask(key: &[u8], offset: u64) -> u8 {
+    let offset = if offset > 0x7FFF {
+        offset % 0x7FFF
+    } else {
+        offset
+    };
+    let idx = ((offset * offset + 71214) % key.len() as u64) as usize;
+    // 与客户端一致，左右移位相同的位数后合并，并不是循环移位
+    let shift = ((idx & 0x7) + 4) % 8;
+    let value = key[idx];
+    (value << shift) | (value >> shift)
+}
+
+#[derive(Debug, Clone)]
+struct Rc4Cipher {
+    key: Vec<u8>,
+    state: Vec<u8>,
+    hash: u32,
+}
+
+impl Rc4Cipher {
+    fn new(key: &[u8]) -> Self {
+        let n = key.len();
+        // 状态表长度与密钥相同，元素只保留低 8 位
+        let mut state: Vec<u8> = (0..n).map(|i| i as u8).collect();
+        let mut j = 0;
+        for i in 0..n {
+            j = (j + state[i] as usize + key[i] as usize) % n;
+            state.swap(i, j);
+        }
+
+        let mut hash: u32 = 1;
+        for &byte in key {
+            if byte == 0 {
+                continue;
+            }
+            let next = hash.wrapping_mul(byte as u32);
+            if next == 0 || next <= hash {
+                break;
+            }
+            hash = next;
+        }
+
+        Self {
+            key: key.to_vec(),
+            state,
+            hash,
+        }
+    }
+
+    fn segment_key(&self, id: u64) -> usize {
+        let n = self.key.len();
+        let seed = self.key[(id % n as u64) as usize];
+        if seed == 0 {
+            return 0;
+        }
+        let idx = (self.hash as f64 / ((id + 1) * seed as u64) as f64 * 100.0) as u64;
+        (idx % n as u64) as usize
+    }
+
+    fn decrypt(&self, mut offset: u64, buf: &mut [u8]) {
+        let mut buf = buf;
+        // 前 128 字节直接与密钥异或
+        if offset < RC4_FIRST_SEGMENT_SIZE {
+            let len = buf.len().min((RC4_FIRST_SEGMENT_SIZE - offset) as usize);
+            let (head, rest) = buf.split_at_mut(len);
+            for (i, byte) in head.iter_mut().enumerate() {
+                *byte ^= self.key[self.segment_key(offset + i as u64)];
+            }
+            offset += len as u64;
+            buf = rest;
+        }
+        // 之后每 5120 字节为一段，每段重新开始生成密钥流
+        while !buf.is_empty() {
+            let len = buf
+                .len()
+                .min((RC4_SEGMENT_SIZE - offset % RC4_SEGMENT_SIZE) as usize);
+            let (segment, rest) = buf.split_at_mut(len);
+            self.decrypt_segment(offset, segment);
+            offset += len as u64;
+            buf = rest;
+        }
+    }
+
+    /// 解密一段之内的数据
+    fn decrypt_segment(&self, offset: u64, buf: &mut [u8]) {
+        let n = self.key.len();
+        let mut state = self.state.clone();
+        let (mut j, mut k) = (0, 0);
+        let skip =
+            (offset % RC4_SEGMENT_SIZE) as usize + self.segment_key(offset / RC4_SEGMENT_SIZE);
+        for i in 0..skip + buf.len() {
+            j = (j + 1) % n;
+            k = (state[j] as usize + k) % n;
+            state.swap(j, k);
+            if i >= skip {
+                buf[i - skip] ^= state[(state[j] as usize + state[k] as usize) % n];
+            }
+        }
+    }
+}
+
+/// 解密文件末尾的 ekey，得到解密音频所用的密钥。
+/// 支持以 `QQMusic EncV2,Key:` 开头的新版 ekey
+pub fn derive_key(ekey: &[u8]) -> Result<Vec<u8>, NcmError> {
+    let mut raw = decode_base64(ekey)?;
+    if let Some(v2) = raw.strip_prefix(EKEY_V2_PREFIX) {
+        let buf = tea_decrypt(v2, &EKEY_V2_TEA_KEY1)?;
+        let buf = tea_decrypt(&buf, &EKEY_V2_TEA_KEY2)?;
+        raw = decode_base64(&buf)?;
+    }
+    if raw.len() < 16 {
+        return Err(NcmError::InvalidKey(format!(
+            "ekey 过短 ({} 字节)",
+            raw.len()
+        )));
+    }
+
+    // 前 8 字节为明文，与固定序列交错组成 TEA 密钥，解密其余部分
+    let mut tea_key = [0u8; 16];
+    for i in 0..8 {
+        let salt = ((106.0 + i as f64 * 0.1).tan() * 100.0).abs() as u8;
+        tea_key[i * 2] = salt;
+        tea_key[i * 2 + 1] = raw[i];
+    }
+    let mut key = raw[..8].to_vec();
+    key.extend(tea_decrypt(&raw[8..], &tea_key)?);
+    Ok(key)
+}
+
+fn decode_base64(data: &[u8]) -> Result<Vec<u8>, NcmError> {
+    let data = data.trim_ascii();
+    general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| NcmError::InvalidKey(format!("ekey Base64 解码失败: {}", e)))
+}
+
+/// 腾讯使用的 TEA 变体：16 轮，块之间以类似 CBC 的方式链接，
+/// 明文前有随机填充和 2 字节盐，末尾有 7 个零字节
+fn tea_decrypt(data: &[u8], key: &[u8; 16]) -> Result<Vec<u8>, NcmError> {
+    let invalid = |reason: &str| NcmError::InvalidKey(format!("ekey TEA 解密失败: {}", reason));
+    if !data.len().is_multiple_of(8) || data.len() < 16 {
+        return Err(invalid("长度不是 8 的倍数或过短"));
+    }
+    let key = [
+        BigEndian::read_u32(&key[0..4]),
+        BigEndian::read_u32(&key[4..8]),
+        BigEndian::read_u32(&key[8..12]),
+        BigEndian::read_u32(&key[12..16]),
+    ];
+
+    // 每块的明文为 D(密文 ^ 上一块的中间结果) ^ 上一块的密文
+    let mut plain = Vec::with_capacity(data.len());
+    let mut state = [0u8; 8];
+    let mut prev = [0u8; 8];
+    for block in data.chunks_exact(8) {
+        for (s, c) in state.iter_mut().zip(block) {
+            *s ^= c;
+        }
+        tea_decrypt_block(&mut state, &key);
+        plain.extend(state.iter().zip(&prev).map(|(s, p)| s ^ p));
+        prev.copy_from_slice(block);
+    }
+
+    let pad_len = (plain[0] & 0x7) as usize;
+    let start = 1 + pad_len + TEA_SALT_LEN;
+    let end = plain.len() - TEA_ZERO_LEN;
+    if start > end {
+        return Err(invalid("填充长度无效"));
+    }
+    if plain[end..].iter().any(|&b| b != 0) {
+        return Err(invalid("末尾校验失败"));
+    }
+    Ok(plain[start..end].to_vec())
+}
+
+fn tea_decrypt_block(block: &mut [u8; 8], key: &[u32; 4]) {
+    let mut v0 = BigEndian::read_u32(&block[0..4]);
+    let mut v1 = BigEndian::read_u32(&block[4..8]);
+    let mut sum = TEA_DELTA.wrapping_mul(TEA_ROUNDS);
+    for _ in 0..TEA_ROUNDS {
+        v1 = v1.wrapping_sub(
+            (v0 << 4).wrapping_add(key[2]) ^ v0.wrapping_add(sum) ^ (v0 >> 5).wrapping_add(key[3]),
+        );
+        v0 = v0.wrapping_sub(
+            (v1 << 4).wrapping_add(key[0]) ^ v1.wrapping_add(sum) ^ (v1 >> 5).wrapping_add(key[1]),
+        );
+        sum = sum.wrapping_sub(TEA_DELTA);
+    }
+    BigEndian::write_u32(&mut block[0..4], v0);
+    BigEndian::write_u32(&mut block[4..8], v1);
+}
+
+/// 解析 v2 文件末尾，返回 ekey（没有时为 `None`）和音频数据的长度
+fn read_trailer<R: Read + Seek>(reader: &mut R) -> Result<(Option<Vec<u8>>, u64), NcmError> {
+    let file_len = stream_len(reader)?;
+    let read_at = |reader: &mut R, offset: u64, len: u64| -> Result<Vec<u8>, NcmError> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::with_capacity(len as usize);
+        reader.take(len).read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    if file_len < 8 {
+        return Ok((None, file_len));
+    }
+
+    let tail = read_at(reader, file_len - 8, 8)?;
+    if &tail[4..] == b"STag" {
+        return Err(NcmError::KeyNotEmbedded("STag".to_string()));
+    }
+    if tail == b"musicex\0" {
+        return Err(NcmError::KeyNotEmbedded("musicex".to_string()));
+    }
+    if &tail[4..] == b"QTag" {
+        // 大端长度，内容为 `ekey,歌曲ID,2`
+        let meta_len = BigEndian::read_u32(&tail[..4]) as u64;
+        let audio_len = file_len
+            .checked_sub(8 + meta_len)
+            .ok_or_else(|| NcmError::InvalidKey(format!("QTag 长度 {} 超出文件大小", meta_len)))?;
+        let meta = read_at(reader, audio_len, meta_len)?;
+        let ekey = meta.split(|&b| b == b',').next().unwrap_or_default();
+        return Ok((Some(ekey.to_vec()), audio_len));
+    }
+
+    // 小端长度，其前为 ekey；长度不合理时认为没有密钥，使用固定掩码表
+    let key_len = LittleEndian::read_u32(&tail[4..]) as u64;
+    match file_len.checked_sub(4 + key_len) {
+        Some(audio_len) if key_len != 0 && key_len <= 0xFFFF => {
+            let mut ekey = read_at(reader, audio_len, key_len)?;
+            while ekey.last() == Some(&0) {
+                ekey.pop();
+            }
+            Ok((Some(ekey), audio_len))
+        }
+        _ => Ok((None, file_len)),
+    }
+}
+
+/// 解密 QMC 文件的读取器，位置与原始文件中的音频数据一一对应
+pub type QmcReader<R> = CipherReader<R, QmcCipher>;
+
+impl<R> QmcReader<R> {
+    /// 使用 v1 的固定掩码表，`inner` 的当前位置必须为文件开头
+    pub fn new(inner: R) -> Self {
+        Self::with_cipher(inner, QmcCipher::default(), None)
+    }
+
+    /// 使用指定的算法，`audio_len` 为音频数据的长度（不含文件末尾的密钥），
+    /// `inner` 的当前位置必须为文件开头
+    pub fn with_cipher(inner: R, cipher: QmcCipher, audio_len: Option<u64>) -> Self {
+        Self {
+            inner,
+            cipher,
+            audio_offset: 0,
+            pos: 0,
+            len: audio_len,
+        }
+    }
+}
+
+/// 根据扩展名得到实际的音频格式，如 `qmcflac`、`mflac` 得到 `flac`
+pub fn format_from_extension(ext: &str) -> Option<&'static str> {
+    extension_info(ext).map(|(format, _)| format)
+}
+
+/// 扩展名对应的格式以及是否为 v2
+fn extension_info(ext: &str) -> Option<(&'static str, bool)> {
+    let ext = ext.to_lowercase();
+    EXTENSION_FORMATS
+        .iter()
+        .find(|(e, _, _)| *e == ext)
+        .map(|&(_, format, v2)| (format, v2))
+}
+
+/// QQ 音乐 QMC 文件的 [`decryptor::Decryptor`] 实现
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QmcDecryptor;
+
+impl QmcDecryptor {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl decryptor::Decryptor for QmcDecryptor {
+    fn name(&self) -> &'static str {
+        "qmc"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[
+            "qmc0", "qmc3", "qmcflac", "qmcogg", "mflac", "mflac0", "mgg", "mgg0", "mgg1", "mggl",
+        ]
+    }
+
+    fn sniff(&self, _header: &[u8]) -> bool {
+        // 没有固定文件头，只按扩展名识别
+        false
+    }
+
+    fn open(&self, reader: Box<dyn ReadSeek>) -> Result<OpenedTrack, NcmError> {
+        self.open_with_path(reader, None)
+    }
+
+    fn open_with_path(
+        &self,
+        mut reader: Box<dyn ReadSeek>,
+        path: Option<&Path>,
+    ) -> Result<OpenedTrack, NcmError> {
+        let (claimed, v2) = path
+            .and_then(|p| p.extension())
+            .and_then(|ext| ext.to_str())
+            .and_then(extension_info)
+            .map_or((None, false), |(format, v2)| (Some(format), v2));
+
+        let (cipher, audio_len) = if v2 {
+            let (ekey, audio_len) = read_trailer(&mut reader)?;
+            let key = ekey.as_deref().map(derive_key).transpose()?;
+            (
+                QmcCipher::from_key(&key.unwrap_or_default()),
+                Some(audio_len),
+            )
+        } else {
+            (QmcCipher::default(), None)
+        };
+        let version = if v2 { "v2" } else { "v1" };
+
+        reader.seek(SeekFrom::Start(0))?;
+        let mut audio = QmcReader::with_cipher(reader, cipher, audio_len);
+        let container = sniff::read_container(&mut audio)?;
+
+        let mut warnings = Vec::new();
+        match (claimed, container) {
+            (Some(claimed), Some(actual)) if claimed != actual => {
+                warnings.push(NcmError::FormatMismatch {
+                    claimed: claimed.to_string(),
+                    actual: actual.to_string(),
+                })
+            }
+            (_, None) => warnings.push(NcmError::UnknownContainer(0)),
+            _ => {}
+        }
+
+        let format = container.or(claimed).unwrap_or("mp3");
+        let metadata = NcmMetadata {
+            format: Some(format.to_string()),
+            ..tag::read_metadata(&mut audio, format)
+        };
+
+        Ok(OpenedTrack {
+            format: format.to_string(),
+            version: Some(format!("{} ({})", version, audio.cipher.name())),
+            key_set: None,
+            metadata,
+            raw_metadata: None,
+            cover: None,
+            warnings,
+            audio: Box::new(audio),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// 从 `offset` 开始解密 `len` 个零字节，得到的就是掩码
+    fn mask(cipher: &QmcCipher, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        cipher.decrypt(offset, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn static_cipher() {
+        let cipher = QmcCipher::default();
+        assert_eq!(
+            mask(&cipher, 0, 8),
+            [0xC3, 0x4A, 0xD6, 0xCA, 0x90, 0x67, 0xF7, 0x52]
+        );
+        // 0x7FFF 之后的偏移按 0x7FFF 取模
+        assert_eq!(
+            mask(&cipher, 0x7FFC, 8),
+            [0x90, 0xCA, 0xD6, 0x4A, 0x4A, 0xD6, 0xCA, 0x90]
+        );
+        assert_eq!(
+            mask(&cipher, 0xFFFC, 8),
+            [0xCA, 0xD6, 0xC3, 0x4A, 0xD6, 0xCA, 0x90, 0x67]
+        );
+    }
+
+    // 以下 ekey 和掩码由另外按参考实现（unlock-music）编写的程序生成
+    const MAP_KEY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// 超过 300 字节、不含 0 的 RC4 密钥
+    fn rc4_key() -> Vec<u8> {
+        (0..512).map(|i| ((i * 37 + 11) % 251 + 1) as u8).collect()
+    }
+
+    #[test]
+    fn derive_key_v1_and_v2() {
+        let v1 = b"MDEyMzQ1NjcOeWcy7fbxfp92b3K90lpGk23ffy+BS/m1TS+5KtimlwaryWluO9cS";
+        assert_eq!(derive_key(v1).unwrap(), MAP_KEY);
+
+        let v2 = b"UVFNdXNpYyBFbmNWMixLZXk6Vhqr789N+eUleg75+1I+fStxjEb+dMcf9kq95ihDObh9\
+                   Mmsy63pGpdywLRgw/kdfEA0uTBDaybIJCR/VjSaABHtMHFXdd9nl2Ed1PK56csMx8H0S\
+                   Ysvyi8xSADWsJYTU";
+        assert_eq!(derive_key(v2).unwrap(), MAP_KEY);
+
+        // 篡改最后一个密文块，末尾的零字节校验失败
+        let mut raw = general_purpose::STANDARD.decode(v1).unwrap();
+        *raw.last_mut().unwrap() ^= 1;
+        let tampered = general_purpose::STANDARD.encode(raw);
+        assert!(matches!(
+            derive_key(tampered.as_bytes()),
+            Err(NcmError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            derive_key(b"MDEyMzQ1Njc="),
+            Err(NcmError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn map_cipher() {
+        let cipher = QmcCipher::from_key(MAP_KEY);
+        assert_eq!(cipher.name(), "map");
+        assert_eq!(
+            mask(&cipher, 0, 8),
+            [0xDD, 0xBE, 0x41, 0x3C, 0xBF, 0xBE, 0xDD, 0x00]
+        );
+        assert_eq!(
+            mask(&cipher, 0x7FFC, 8),
+            [0xBF, 0xBE, 0xDD, 0x00, 0xBE, 0x41, 0x3C, 0xBF]
+        );
+    }
+
+    #[test]
+    fn rc4_cipher() {
+        let cipher = QmcCipher::from_key(&rc4_key());
+        assert_eq!(cipher.name(), "rc4");
+        // 分别跨过第一段的 128 字节和之后每段 5120 字节的边界
+        let expected: [(u64, [u8; 8]); 4] = [
+            (0, [0xC5, 0xA7, 0xD1, 0xCC, 0x32, 0x79, 0x76, 0xCA]),
+            (124, [0xBD, 0x2E, 0x3B, 0x0C, 0x75, 0xEB, 0xC8, 0x20]),
+            (5116, [0xE4, 0x79, 0x5A, 0x5E, 0xDC, 0xD5, 0x44, 0xE7]),
+            (10236, [0x1A, 0x8C, 0x3A, 0xA9, 0x5A, 0x87, 0xAF, 0xA7]),
+        ];
+        for (offset, bytes) in expected {
+            assert_eq!(mask(&cipher, offset, 8), bytes, "偏移 {}", offset);
+        }
+
+        // 一次解密与分块解密的结果相同
+        let whole = mask(&cipher, 0, 12000);
+        for (offset, bytes) in expected {
+            let offset = offset as usize;
+            assert_eq!(whole[offset..offset + 8], bytes);
+        }
+        let mut pieces = Vec::new();
+        for chunk in [1, 126, 7, 5000, 3, 6863] {
+            pieces.extend(mask(&cipher, pieces.len() as u64, chunk));
+        }
+        assert_eq!(pieces, whole);
+    }
+
+    fn trailer(tail: &[u8]) -> Result<(Option<Vec<u8>>, u64), NcmError> {
+        let mut data = vec![0xAA; 100];
+        data.extend_from_slice(tail);
+        read_trailer(&mut Cursor::new(data))
+    }
+
+    #[test]
+    fn read_trailers() {
+        let meta = b"ekey,12345,2";
+        let mut tail = meta.to_vec();
+        tail.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+        tail.extend_from_slice(b"QTag");
+        assert_eq!(trailer(&tail).unwrap(), (Some(b"ekey".to_vec()), 100));
+
+        let mut tail = b"ekey\0\0".to_vec();
+        tail.extend_from_slice(&6u32.to_le_bytes());
+        assert_eq!(trailer(&tail).unwrap(), (Some(b"ekey".to_vec()), 100));
+
+        assert!(matches!(
+            trailer(b"\0\0\0\x10STag"),
+            Err(NcmError::KeyNotEmbedded(tag)) if tag == "STag"
+        ));
+        assert!(matches!(
+            trailer(b"musicex\0"),
+            Err(NcmError::KeyNotEmbedded(tag)) if tag == "musicex"
+        ));
+        // 长度不合理时认为没有密钥
+        assert_eq!(trailer(&[0xFF; 4]).unwrap(), (None, 104));
+        assert!(matches!(
+            trailer(&[0, 0, 1, 0, b'Q', b'T', b'a', b'g']),
+            Err(NcmError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn extension_formats() {
+        assert_eq!(extension_info("qmc0"), Some(("mp3", false)));
+        assert_eq!(extension_info("qmc3"), Some(("mp3", false)));
+        assert_eq!(extension_info("QMCFLAC"), Some(("flac", false)));
+        assert_eq!(extension_info("qmcogg"), Some(("ogg", false)));
+        assert_eq!(extension_info("mflac0"), Some(("flac", true)));
+        assert_eq!(extension_info("mgg1"), Some(("ogg", true)));
+        assert_eq!(extension_info("ncm"), None);
+        assert_eq!(format_from_extension("mflac"), Some("flac"));
+
+        let decryptor = QmcDecryptor::new();
+        for (ext, _, _) in EXTENSION_FORMATS {
+            assert!(decryptor::Decryptor::extensions(&decryptor).contains(ext));
+        }
+    }
+}
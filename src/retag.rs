@@ -0,0 +1,54 @@
+//! 为网易云客户端直接下载的 mp3 / flac 文件恢复标签。
+//!
+//! 这些文件不是 NCM 格式，但带有一条 `163 key(Don't modify):` 开头的注释，
+//! 内容就是 NCM 元数据区块异或 `0x63` 之后的结果。解密后可以重新写入曲名、艺术家、专辑和封面。
+
+use crate::keys::KeySet;
+use crate::metadata::NcmMetadata;
+use crate::ncm::{self, NcmError};
+use crate::{sniff, tag};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// 一次重新写入标签的结果
+#[derive(Debug, Clone)]
+pub struct RetagReport {
+    /// 音频的实际格式（`mp3` 或 `flac`）
+    pub format: String,
+    /// 成功解密注释的密钥组名称
+    pub key_set: String,
+    /// 解密得到的元数据
+    pub metadata: NcmMetadata,
+    /// 实际写入的标签名称，如 `title`、`cover`
+    pub tags_written: Vec<&'static str>,
+    /// 嵌入的封面图片路径，没有同名图片时为 `None`，此时保留文件中原有的封面
+    pub cover_path: Option<PathBuf>,
+}
+
+/// 读取 `path` 中的 `163 key` 注释，依次尝试 `key_sets` 解密，并用得到的元数据重新写入标签。
+///
+/// 元数据中只有封面的链接，如果音频旁有同名的 `.jpg` / `.png`（如 [`crate::CoverPolicy::Sidecar`]
+/// 的输出），会将其作为封面嵌入
+pub fn retag(path: &Path, key_sets: &[KeySet]) -> Result<RetagReport, NcmError> {
+    let format = sniff::read_container(&mut File::open(path)?)?
+        .filter(|&format| tag::supports_format(format))
+        .ok_or_else(|| NcmError::Unsupported("只支持 mp3 和 flac 文件".to_string()))?;
+    let comment = tag::read_163_key(path, format)?
+        .ok_or_else(|| NcmError::Metadata("文件中没有 163 key 注释".to_string()))?;
+    let (metadata, key_set) = ncm::decrypt_163_key(&comment, key_sets)?;
+
+    let cover_path = ["jpg", "jpeg", "png"]
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|cover| cover.is_file());
+    let cover = cover_path.as_ref().map(fs::read).transpose()?;
+    let tags_written = tag::tag_path(path, format, &metadata, cover.as_deref())?;
+
+    Ok(RetagReport {
+        format: format.to_string(),
+        key_set: key_set.name.clone(),
+        metadata,
+        tags_written,
+        cover_path,
+    })
+}
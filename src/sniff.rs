@@ -0,0 +1,43 @@
+//! 根据解密后音频数据的开头识别实际的容器格式。
+
+use crate::decryptor::SNIFF_LEN;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// 根据音频数据开头的字节识别容器格式，返回小写扩展名（如 `flac`、`mp3`），无法识别时返回 `None`
+pub fn sniff_container(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"fLaC") {
+        Some("flac")
+    } else if header.starts_with(b"ID3") || is_mpeg_frame_sync(header) {
+        Some("mp3")
+    } else if header.starts_with(b"OggS") {
+        Some("ogg")
+    } else if header.get(4..8) == Some(b"ftyp") {
+        Some("m4a")
+    } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+        Some("wav")
+    } else if header.starts_with(b"MAC ") {
+        Some("ape")
+    } else {
+        None
+    }
+}
+
+/// MPEG 音频帧同步字：11 位全 1，且 layer 不为保留值 00（排除 AAC ADTS）
+fn is_mpeg_frame_sync(header: &[u8]) -> bool {
+    match header {
+        [0xFF, b, ..] => b & 0xE0 == 0xE0 && b & 0x06 != 0,
+        _ => false,
+    }
+}
+
+/// 从当前位置读取最多 [`SNIFF_LEN`] 个字节识别容器格式，返回前会定位回原位置
+pub fn read_container<R: Read + Seek>(reader: &mut R) -> io::Result<Option<&'static str>> {
+    let start = reader.stream_position()?;
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    reader
+        .by_ref()
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(sniff_container(&header))
+}
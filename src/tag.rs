@@ -0,0 +1,546 @@
+use crate::metadata::{NcmArtist, NcmMetadata};
+use crate::ncm::{META_PREFIX, NcmError};
+use id3::frame::Comment;
+use id3::{Tag, TagLike, Version};
+use metaflac::block::{Block, BlockType, PictureType};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// 为磁盘上的音频文件写入标签，目前支持 mp3 和 flac，其他格式原样保留。
+/// 返回实际写入的标签名称，如 `title`、`cover`。
+pub fn tag_path(
+    path: &Path,
+    format: &str,
+    meta: &NcmMetadata,
+    cover: Option<&[u8]>,
+) -> Result<Vec<&'static str>, NcmError> {
+    let written = if format == "mp3" {
+        // **修正**: 尝试读取现有标签，如果不存在则创建新的。
+        // 这样可以保留解密后的音频流中已有的标签。
+        let mut tag = Tag::read_from_path(path).unwrap_or_else(|_| Tag::new());
+        let written = apply_id3(&mut tag, meta, cover);
+        tag.write_to_path(path, Version::Id3v23)?;
+        written
+    } else if format == "flac" {
+        let mut tag = read_flac_path(path)?;
+        let written = apply_vorbis(&mut tag, meta, cover);
+        tag.write_to_path(path)?;
+        written
+    } else {
+        Vec::new()
+    };
+    Ok(written)
+}
+
+/// 从 mp3 / flac 音频中已有的标签读取曲名、艺术家、专辑和音轨号，用于本身不带元数据的格式。
+/// 没有标签或读取失败时返回空的元数据，返回前会将 `reader` 定位回原位置
+pub fn read_metadata<R: Read + Seek>(reader: &mut R, format: &str) -> NcmMetadata {
+    let Ok(start) = reader.stream_position() else {
+        return NcmMetadata::default();
+    };
+    let artists = |names: Vec<String>| -> Vec<NcmArtist> {
+        names
+            .into_iter()
+            .filter(|name| !name.is_empty())
+            .map(|name| NcmArtist { name, id: 0 })
+            .collect()
+    };
+    let meta = if format == "mp3" {
+        Tag::read_from2(&mut *reader).ok().map(|tag| NcmMetadata {
+            music_name: tag.title().map(str::to_string),
+            album: tag.album().map(str::to_string),
+            artist: artists(
+                tag.artist()
+                    .map(|a| a.split('/').map(str::to_string).collect())
+                    .unwrap_or_default(),
+            ),
+            track_no: tag.track().map(u64::from),
+            ..Default::default()
+        })
+    } else if format == "flac" {
+        read_flac_tag(&mut *reader).ok().and_then(|tag| {
+            let comments = tag.vorbis_comments()?;
+            let first = |values: Option<&Vec<String>>| values.and_then(|v| v.first()).cloned();
+            Some(NcmMetadata {
+                music_name: first(comments.title()),
+                album: first(comments.album()),
+                artist: artists(comments.artist().cloned().unwrap_or_default()),
+                track_no: comments.track().map(u64::from),
+                ..Default::default()
+            })
+        })
+    } else {
+        None
+    };
+    let _ = reader.seek(SeekFrom::Start(start));
+    meta.unwrap_or_default()
+}
+
+/// 读取网易云客户端下载的 mp3 / flac 中 `163 key(Don't modify):` 开头的注释，
+/// 没有标签或没有该注释时返回 `None`
+pub fn read_163_key(path: &Path, format: &str) -> Result<Option<String>, NcmError> {
+    let comment = if format == "mp3" {
+        let tag = match Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // 一般写在 COMM 帧，部分文件写在 TXXX 帧
+        tag.comments()
+            .map(|c| c.text.as_str())
+            .chain(tag.extended_texts().map(|t| t.value.as_str()))
+            .find(|text| is_163_key(text))
+            .map(str::to_string)
+    } else if format == "flac" {
+        let tag = read_flac_path(path)?;
+        // 一般写在 DESCRIPTION 字段，优先查找它，再查找其他字段
+        tag.vorbis_comments().and_then(|comments| {
+            comments
+                .get("DESCRIPTION")
+                .into_iter()
+                .flatten()
+                .chain(comments.comments.values().flatten())
+                .find(|text| is_163_key(text))
+                .cloned()
+        })
+    } else {
+        None
+    };
+    Ok(comment)
+}
+
+fn is_163_key(text: &str) -> bool {
+    text.trim_start().as_bytes().starts_with(META_PREFIX)
+}
+
+/// 写入 `163 key(Don't modify):` 注释（由 [`crate::ncm::encode_163_key`] 生成），
+/// mp3 写为语言 `XXX`、描述为空的 COMM 帧，flac 写为 `DESCRIPTION` 字段。
+/// 只替换已有的 `163 key` 注释，用户自己的注释保持不变
+pub fn write_163_key(path: &Path, format: &str, comment: &str) -> Result<(), NcmError> {
+    if format == "mp3" {
+        let mut tag = Tag::read_from_path(path).unwrap_or_else(|_| Tag::new());
+        let old: Vec<String> = tag
+            .comments()
+            .filter(|c| is_163_key(&c.text))
+            .map(|c| c.text.clone())
+            .collect();
+        for text in &old {
+            tag.remove_comment(None, Some(text));
+        }
+        tag.add_frame(Comment {
+            lang: "XXX".to_string(),
+            description: String::new(),
+            text: comment.to_string(),
+        });
+        tag.write_to_path(path, Version::Id3v23)?;
+    } else if format == "flac" {
+        let mut tag = read_flac_path(path)?;
+        let mut values = vec![comment.to_string()];
+        if let Some(existing) = tag.get_vorbis("DESCRIPTION") {
+            values.extend(
+                existing
+                    .filter(|text| !is_163_key(text))
+                    .map(str::to_string),
+            );
+        }
+        tag.set_vorbis("DESCRIPTION", values);
+        tag.write_to_path(path)?;
+    }
+    Ok(())
+}
+
+/// 是否支持为该格式写入标签
+pub fn supports_format(format: &str) -> bool {
+    matches!(format, "mp3" | "flac")
+}
+
+/// 为内存中的音频数据写入标签，返回带标签的完整音频数据
+pub fn tag_bytes(
+    audio: Vec<u8>,
+    format: &str,
+    meta: &NcmMetadata,
+    cover: Option<&[u8]>,
+) -> Result<Vec<u8>, NcmError> {
+    tag_bytes_written(audio, format, meta, cover).map(|(audio, _)| audio)
+}
+
+/// 与 [`tag_bytes`] 相同，同时返回实际写入的标签名称
+pub(crate) fn tag_bytes_written(
+    audio: Vec<u8>,
+    format: &str,
+    meta: &NcmMetadata,
+    cover: Option<&[u8]>,
+) -> Result<(Vec<u8>, Vec<&'static str>), NcmError> {
+    if format == "mp3" {
+        let mut cursor = Cursor::new(audio);
+        let mut tag = Tag::read_from2(&mut cursor).unwrap_or_else(|_| Tag::new());
+        let written = apply_id3(&mut tag, meta, cover);
+        cursor.set_position(0);
+        tag.write_to_file(&mut cursor, Version::Id3v23)?;
+        Ok((cursor.into_inner(), written))
+    } else if format == "flac" {
+        let mut cursor = Cursor::new(audio);
+        let mut tag = read_flac_tag(&mut cursor)?;
+        let written = apply_vorbis(&mut tag, meta, cover);
+        cursor.set_position(0);
+        let frames = metaflac::Tag::skip_metadata(&mut cursor);
+
+        // 与 write_to_path 一致，在最后保留 1024 字节的填充
+        tag.remove_blocks(BlockType::Padding);
+        tag.push_block(Block::Padding(1024));
+        let mut output = Vec::with_capacity(frames.len() + 4096);
+        tag.write_to(&mut output)?;
+        output.extend_from_slice(&frames);
+        Ok((output, written))
+    } else {
+        Ok((audio, Vec::new()))
+    }
+}
+
+fn read_flac_path(path: &Path) -> Result<metaflac::Tag, NcmError> {
+    check_flac_blocks(&mut BufReader::new(File::open(path)?))?;
+    Ok(metaflac::Tag::read_from_path(path)?)
+}
+
+fn read_flac_tag<R: Read + Seek>(reader: &mut R) -> Result<metaflac::Tag, NcmError> {
+    check_flac_blocks(reader)?;
+    Ok(metaflac::Tag::read_from(reader)?)
+}
+
+/// 在交给 metaflac 解析之前逐块检查 FLAC 元数据，返回前将 `reader` 定位回原位置。
+///
+/// metaflac 直接按块中记录的长度切片，遇到损坏的块（如过短的 STREAMINFO、超出块末尾的注释长度）
+/// 会越界 panic，一个损坏的文件就会中断整批转换，这里按它的解析方式提前检查并返回错误
+fn check_flac_blocks<R: Read + Seek>(reader: &mut R) -> Result<(), NcmError> {
+    let start = reader.stream_position()?;
+    let result = walk_flac_blocks(reader);
+    reader.seek(SeekFrom::Start(start))?;
+    result
+}
+
+fn walk_flac_blocks<R: Read>(reader: &mut R) -> Result<(), NcmError> {
+    let mut ident = [0; 4];
+    reader.read_exact(&mut ident)?;
+    // 与 metaflac 一致，跳过开头的 ID3v2 标签
+    if ident.starts_with(b"ID3") && [2, 3, 4].contains(&ident[3]) {
+        let mut header = [0; 6];
+        reader.read_exact(&mut header)?;
+        let size = header[2..]
+            .iter()
+            .fold(0, |size, &b| (size << 7) | (b & 0x7F) as u64);
+        let footer = if header[1] & 0x10 != 0 { 10 } else { 0 };
+        io::copy(&mut reader.take(size + footer), &mut io::sink())?;
+        reader.read_exact(&mut ident)?;
+    }
+    if &ident != b"fLaC" {
+        // 不是 FLAC 文件，由 metaflac 返回错误
+        return Ok(());
+    }
+
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        let mut body = Vec::new();
+        reader.take(len as u64).read_to_end(&mut body)?;
+        check_flac_block(header[0] & 0x7F, &body)?;
+        if header[0] & 0x80 != 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// 检查 metaflac 解析该类型的块时读取的每个字段都在块内
+fn check_flac_block(block_type: u8, body: &[u8]) -> Result<(), NcmError> {
+    let mut fields = Fields(body);
+    let (name, valid) = match block_type {
+        0 => ("STREAMINFO", body.len() >= 34),
+        2 => ("APPLICATION", body.len() >= 4),
+        4 => ("VORBIS_COMMENT", fields.vorbis_comment().is_some()),
+        5 => ("CUESHEET", fields.cue_sheet().is_some()),
+        6 => ("PICTURE", fields.picture().is_some()),
+        _ => return Ok(()),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(NcmError::Tagging(format!(
+            "FLAC 元数据块损坏: {} 块 ({} 字节) 的字段超出块末尾",
+            name,
+            body.len()
+        )))
+    }
+}
+
+/// 按顺序读取块中的字段，超出块末尾时返回 `None`
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (field, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(field)
+    }
+
+    fn len_le(&mut self) -> Option<usize> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+
+    fn len_be(&mut self) -> Option<usize> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+
+    fn vorbis_comment(&mut self) -> Option<()> {
+        let vendor_len = self.len_le()?;
+        self.take(vendor_len)?;
+        for _ in 0..self.len_le()? {
+            let len = self.len_le()?;
+            // 没有 `=` 的注释同样会让 metaflac panic
+            self.take(len)?.contains(&b'=').then_some(())?;
+        }
+        Some(())
+    }
+
+    fn cue_sheet(&mut self) -> Option<()> {
+        // 目录号、引导样本数、标志和保留字节
+        self.take(128 + 8 + 1 + 258)?;
+        for _ in 0..self.take(1)?[0] {
+            // 偏移、音轨号、ISRC、标志和保留字节
+            self.take(8 + 1 + 12 + 1 + 13)?;
+            let indices = self.take(1)?[0] as usize;
+            self.take(indices * 12)?;
+        }
+        Some(())
+    }
+
+    fn picture(&mut self) -> Option<()> {
+        self.take(4)?;
+        let mime_len = self.len_be()?;
+        self.take(mime_len)?;
+        let description_len = self.len_be()?;
+        self.take(description_len)?;
+        // 宽、高、色深和颜色数
+        self.take(16)?;
+        let data_len = self.len_be()?;
+        self.take(data_len)?;
+        Some(())
+    }
+}
+
+fn cover_mime_type(img_data: &[u8]) -> &'static str {
+    if img_data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+fn apply_id3(tag: &mut Tag, meta: &NcmMetadata, cover: Option<&[u8]>) -> Vec<&'static str> {
+    let mut written = vec!["title", "album", "artist"];
+    tag.set_title(meta.title());
+    tag.set_album(meta.album_name());
+    tag.set_artist(meta.artist_names().join("/"));
+    if let Some(tn) = meta.track_no {
+        tag.set_track(tn as u32);
+        written.push("track");
+    }
+
+    if let Some(img_data) = cover {
+        let picture = id3::frame::Picture {
+            mime_type: cover_mime_type(img_data).to_string(),
+            picture_type: id3::frame::PictureType::CoverFront,
+            description: "Cover".to_string(),
+            data: img_data.to_vec(),
+        };
+        // 移除旧封面，以防重复
+        tag.remove_picture_by_type(id3::frame::PictureType::CoverFront);
+        tag.add_frame(picture);
+        written.push("cover");
+    }
+    written
+}
+
+fn apply_vorbis(
+    tag: &mut metaflac::Tag,
+    meta: &NcmMetadata,
+    cover: Option<&[u8]>,
+) -> Vec<&'static str> {
+    let mut written = vec!["title", "album", "artist"];
+    let comments = tag.vorbis_comments_mut();
+    comments.set_title(vec![meta.title()]);
+    comments.set_album(vec![meta.album_name()]);
+    comments.set_artist(meta.artist_names());
+    if let Some(tn) = meta.track_no {
+        comments.set("TRACKNUMBER", vec![tn.to_string()]);
+        written.push("track");
+    }
+
+    if let Some(img_data) = cover {
+        // 移除旧封面
+        tag.remove_picture_type(PictureType::CoverFront);
+        tag.add_picture(
+            cover_mime_type(img_data),
+            PictureType::CoverFront,
+            img_data.to_vec(),
+        );
+        written.push("cover");
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeySet;
+    use crate::ncm::{decrypt_163_key, encode_163_key};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// 由 `(类型, 内容)` 组成的 FLAC 元数据，最后一块带结束标记
+    fn flac(blocks: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        for (i, (block_type, body)) in blocks.iter().enumerate() {
+            let last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+            data.push(block_type | last);
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+            data.extend_from_slice(body);
+        }
+        data
+    }
+
+    fn vorbis_comment(comments: &[&[u8]]) -> Vec<u8> {
+        let mut body = 0u32.to_le_bytes().to_vec();
+        body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            body.extend_from_slice(comment);
+        }
+        body
+    }
+
+    #[test]
+    fn corrupt_flac_blocks() {
+        let stream_info = [0; 34];
+        let valid = flac(&[(0, &stream_info), (4, &vorbis_comment(&[b"TITLE=t"]))]);
+        let meta = read_metadata(&mut Cursor::new(&valid), "flac");
+        assert_eq!(meta.title(), "t");
+
+        let mut long_comment = vorbis_comment(&[b"TITLE=t"]);
+        long_comment[8] = 0xFF;
+        let mut picture = vec![0; 8];
+        picture[7] = 100;
+        let cue_sheet = [0; 100];
+        for data in [
+            flac(&[(0, &stream_info[..20])]),
+            flac(&[(0, &stream_info), (2, b"ab")]),
+            flac(&[(0, &stream_info), (4, &long_comment)]),
+            flac(&[(0, &stream_info), (4, &vorbis_comment(&[b"TITLE"]))]),
+            flac(&[(0, &stream_info), (5, &cue_sheet)]),
+            flac(&[(0, &stream_info), (6, &picture)]),
+        ] {
+            let mut reader = Cursor::new(&data);
+            assert!(matches!(
+                check_flac_blocks(&mut reader),
+                Err(NcmError::Tagging(_))
+            ));
+            assert_eq!(reader.position(), 0);
+            assert_eq!(read_metadata(&mut reader, "flac"), NcmMetadata::default());
+            assert!(matches!(
+                tag_bytes(data.clone(), "flac", &NcmMetadata::default(), None),
+                Err(NcmError::Tagging(_))
+            ));
+        }
+    }
+
+    /// 临时目录中以测试名区分的文件，测试结束时删除
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("ncmcvt-{}-{}", std::process::id(), name));
+            fs::write(&path, data).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn sample_163_key(name: &str) -> (NcmMetadata, String) {
+        let meta = NcmMetadata {
+            music_id: Some(1234),
+            music_name: Some(name.to_string()),
+            format: Some("mp3".to_string()),
+            ..Default::default()
+        };
+        let comment = encode_163_key(&meta, &KeySet::default().meta_key).unwrap();
+        (meta, comment)
+    }
+
+    /// 写入两次 163 key 后读回，应为第二次的内容，且用户注释保持不变
+    fn assert_163_key_round_trip(path: &Path, format: &str) {
+        let (_, old) = sample_163_key("旧曲名");
+        let (meta, comment) = sample_163_key("曲名");
+        write_163_key(path, format, &old).unwrap();
+        write_163_key(path, format, &comment).unwrap();
+
+        let read = read_163_key(path, format).unwrap().unwrap();
+        assert_eq!(read, comment);
+        let key_sets = [KeySet::default()];
+        assert_eq!(decrypt_163_key(&read, &key_sets).unwrap().0, meta);
+    }
+
+    #[test]
+    fn write_163_key_mp3() {
+        let mut tag = Tag::new();
+        tag.set_title("标题");
+        tag.add_frame(Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: "用户注释".to_string(),
+        });
+        let mut data = Vec::new();
+        tag.write_to(&mut data, Version::Id3v23).unwrap();
+        data.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        data.resize(data.len() + 1000, 0);
+        let file = TempFile::new("163-key.mp3", &data);
+
+        assert_163_key_round_trip(&file.0, "mp3");
+        let tag = Tag::read_from_path(&file.0).unwrap();
+        let texts: Vec<&str> = tag.comments().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts.len(), 2);
+        assert!(texts.contains(&"用户注释"));
+        assert_eq!(texts.iter().filter(|text| is_163_key(text)).count(), 1);
+        assert_eq!(tag.title(), Some("标题"));
+    }
+
+    #[test]
+    fn write_163_key_flac() {
+        let comments =
+            vorbis_comment(&["DESCRIPTION=用户注释".as_bytes(), "TITLE=标题".as_bytes()]);
+        let mut data = flac(&[(0, &[0; 34]), (4, &comments)]);
+        data.extend_from_slice(&[0xFF, 0xF8, 0x69, 0x08]);
+        data.resize(data.len() + 1000, 0);
+        let file = TempFile::new("163-key.flac", &data);
+
+        assert_163_key_round_trip(&file.0, "flac");
+        let tag = metaflac::Tag::read_from_path(&file.0).unwrap();
+        let descriptions: Vec<&str> = tag.get_vorbis("DESCRIPTION").unwrap().collect();
+        assert_eq!(descriptions.len(), 2);
+        assert!(is_163_key(descriptions[0]));
+        assert_eq!(descriptions[1], "用户注释");
+        assert_eq!(
+            tag.get_vorbis("TITLE").unwrap().collect::<Vec<_>>(),
+            ["标题"]
+        );
+
+        // 音频数据原样保留
+        let mut file_data = fs::File::open(&file.0).unwrap();
+        let frames = metaflac::Tag::skip_metadata(&mut file_data);
+        assert_eq!(frames[..4], [0xFF, 0xF8, 0x69, 0x08]);
+    }
+}
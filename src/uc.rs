@@ -0,0 +1,421 @@
+//! 网易云音乐客户端的播放缓存（.uc 文件）。
+//!
+//! 缓存文件是原始音频逐字节异或 `0xa3` 的结果，文件名形如 `歌曲ID-码率-MD5.uc`，
+//! 本身不含元数据。元数据可以通过歌曲 ID 从用户提供的映射中查找。
+//!
+//! 客户端会在缓存文件旁写入同名的 `.idx`（Windows 上为 `.idx!`）和 `.info` 文件，
+//! 记录歌曲 ID、码率、MD5 和完整文件的大小，由 [`CacheEntry`] 解析，用于判断缓存是否完整。
+
+use crate::decryptor::{self, OpenedTrack, ReadSeek};
+use crate::metadata::{self, NcmMetadata, id_or_string};
+use crate::ncm::NcmError;
+use crate::{sniff, tag};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const UC_XOR_KEY: u8 = 0xa3;
+
+/// 解密缓存文件的读取器，位置与原始文件一一对应
+pub struct UcReader<R> {
+    inner: R,
+}
+
+impl<R> UcReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// 取回内部的读取器
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for UcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        buf[..n].iter_mut().for_each(|byte| *byte ^= UC_XOR_KEY);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for UcReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// 从缓存文件名中解析歌曲 ID，如 `1234567-320-0123abcd.uc` 得到 `1234567`
+pub fn song_id_from_path(path: &Path) -> Option<u64> {
+    CacheIndex::from_file_name(path).song_id
+}
+
+/// 缓存索引文件中的信息，各字段都可能缺失
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CacheIndex {
+    #[serde(alias = "musicId", alias = "id", deserialize_with = "id_or_string")]
+    pub song_id: Option<u64>,
+    /// 码率，单位与文件名一致（kbps）
+    #[serde(alias = "br", deserialize_with = "id_or_string")]
+    pub bitrate: Option<u64>,
+    /// 完整音频文件的 MD5
+    pub md5: Option<String>,
+    /// 完整音频文件的大小
+    #[serde(
+        alias = "fileSize",
+        alias = "filesize",
+        deserialize_with = "id_or_string"
+    )]
+    pub size: Option<u64>,
+    /// 已缓存的字节范围（含两端），JSON 中为 `"起始 结束"` 形式的字符串
+    #[serde(deserialize_with = "cached_zones")]
+    pub zone: Vec<(u64, u64)>,
+}
+
+impl CacheIndex {
+    /// 解析 `.idx` / `.info` 文件的 JSON 内容
+    pub fn from_json(json: &str) -> Result<Self, NcmError> {
+        metadata::deserialize_json(json)
+    }
+
+    /// 从 `歌曲ID-码率-MD5` 形式的文件名中解析信息
+    pub fn from_file_name(path: &Path) -> Self {
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        let mut parts = stem.splitn(3, '-');
+        Self {
+            song_id: parts.next().and_then(|p| p.parse().ok()),
+            bitrate: parts.next().and_then(|p| p.parse().ok()),
+            md5: parts
+                .next()
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_lowercase()),
+            ..Default::default()
+        }
+    }
+
+    /// 用 `other` 补全缺失的字段
+    fn or(self, other: Self) -> Self {
+        Self {
+            song_id: self.song_id.or(other.song_id),
+            bitrate: self.bitrate.or(other.bitrate),
+            md5: self.md5.or(other.md5),
+            size: self.size.or(other.size),
+            zone: if self.zone.is_empty() {
+                other.zone
+            } else {
+                self.zone
+            },
+        }
+    }
+}
+
+fn cached_zones<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u64, u64)>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|zone| {
+            let mut bounds = zone.split_whitespace().map(str::parse::<u64>);
+            match (bounds.next(), bounds.next(), bounds.next()) {
+                (Some(Ok(start)), Some(Ok(end)), None) if start <= end => Ok((start, end)),
+                _ => Err(serde::de::Error::custom(format!(
+                    "无效的缓存范围: \"{}\"",
+                    zone
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// 一个缓存文件及其索引信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    /// .uc 文件的路径
+    pub path: PathBuf,
+    /// 合并了索引文件和文件名中的信息，索引文件优先
+    pub index: CacheIndex,
+    /// .uc 文件的实际大小
+    pub cached_size: u64,
+}
+
+impl CacheEntry {
+    /// 读取缓存文件旁的 `.idx`、`.idx!` 和 `.info` 文件，都不存在时只使用文件名中的信息
+    pub fn from_path(path: &Path) -> Result<Self, NcmError> {
+        let cached_size = fs::metadata(path)?.len();
+        let mut index = CacheIndex::default();
+        for ext in ["idx", "idx!", "info"] {
+            let sidecar = path.with_extension(ext);
+            if sidecar.is_file() {
+                index = index.or(CacheIndex::from_json(&fs::read_to_string(&sidecar)?)?);
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            index: index.or(CacheIndex::from_file_name(path)),
+            cached_size,
+        })
+    }
+
+    /// 相对完整文件缺少的字节数，索引中没有完整大小时返回 `None`
+    pub fn missing_bytes(&self) -> Option<u64> {
+        let expected = self.index.size?;
+        let available = expected.min(self.cached_size);
+        if self.index.zone.is_empty() {
+            return Some(expected - available);
+        }
+
+        // 合并已缓存的范围，只统计文件中确实存在的部分
+        let mut zones = self.index.zone.clone();
+        zones.sort_unstable();
+        let mut covered = 0;
+        let mut next = 0;
+        for (start, end) in zones {
+            let start = start.max(next);
+            let end = end.saturating_add(1).min(available);
+            if start < end {
+                covered += end - start;
+                next = end;
+            }
+        }
+        Some(expected - covered)
+    }
+
+    /// 缓存是否完整，无法判断时视为完整
+    pub fn is_complete(&self) -> bool {
+        self.missing_bytes().is_none_or(|missing| missing == 0)
+    }
+}
+
+/// 网易云音乐 .uc 缓存文件的 [`decryptor::Decryptor`] 实现
+#[derive(Debug, Clone, Default)]
+pub struct UcDecryptor {
+    songs: HashMap<u64, NcmMetadata>,
+}
+
+impl UcDecryptor {
+    /// 不带元数据映射，输出文件只有从文件名得到的歌曲 ID
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用歌曲 ID 到元数据的映射
+    pub fn with_songs(songs: HashMap<u64, NcmMetadata>) -> Self {
+        Self { songs }
+    }
+
+    /// 从 JSON 文件读取映射，格式为以歌曲 ID 为键、`music:` 元数据为值的对象：
+    ///
+    /// ```json
+    /// { "1234567": { "musicName": "曲名", "artist": [["艺术家", 1]], "album": "专辑" } }
+    /// ```
+    pub fn from_song_map(path: &Path) -> Result<Self, NcmError> {
+        let json = fs::read_to_string(path)?;
+        let mut songs: HashMap<u64, NcmMetadata> = metadata::deserialize_json(&json)?;
+        for (id, meta) in songs.iter_mut() {
+            meta.music_id.get_or_insert(*id);
+        }
+        Ok(Self::with_songs(songs))
+    }
+
+    /// 映射中歌曲的元数据，没有时读出音频中原有的标签，避免用占位的标签覆盖它们
+    fn metadata_for<R: Read + Seek>(
+        &self,
+        song_id: Option<u64>,
+        audio: &mut R,
+        container: Option<&str>,
+    ) -> NcmMetadata {
+        song_id
+            .and_then(|id| self.songs.get(&id).cloned())
+            .unwrap_or_else(|| NcmMetadata {
+                music_id: song_id,
+                ..tag::read_metadata(audio, container.unwrap_or("mp3"))
+            })
+    }
+}
+
+impl decryptor::Decryptor for UcDecryptor {
+    fn name(&self) -> &'static str {
+        "uc"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["uc"]
+    }
+
+    fn sniff(&self, _header: &[u8]) -> bool {
+        // 没有固定文件头，只按扩展名识别。解密后的内容像音频不足以说明是缓存文件，
+        // 其他同样没有文件头的格式也可能碰巧符合
+        false
+    }
+
+    fn open(&self, reader: Box<dyn ReadSeek>) -> Result<OpenedTrack, NcmError> {
+        self.open_with_path(reader, None)
+    }
+
+    fn open_with_path(
+        &self,
+        reader: Box<dyn ReadSeek>,
+        path: Option<&Path>,
+    ) -> Result<OpenedTrack, NcmError> {
+        let mut warnings = Vec::new();
+        let entry = path
+            .map(CacheEntry::from_path)
+            .transpose()
+            .unwrap_or_else(|e| {
+                warnings.push(e);
+                None
+            });
+        let song_id = match &entry {
+            Some(entry) => entry.index.song_id,
+            None => path.and_then(song_id_from_path),
+        };
+        if let Some(entry) = &entry
+            && let Some(missing) = entry.missing_bytes()
+            && missing > 0
+        {
+            warnings.push(NcmError::IncompleteCache {
+                missing,
+                expected: entry.index.size.unwrap_or_default(),
+            });
+        }
+
+        let mut audio = UcReader::new(reader);
+        audio.seek(SeekFrom::Start(0))?;
+        let container = sniff::read_container(&mut audio)?;
+        let metadata = self.metadata_for(song_id, &mut audio, container);
+
+        if let (Some(claimed), Some(actual)) = (&metadata.format, container)
+            && claimed.to_lowercase() != actual
+        {
+            warnings.push(NcmError::FormatMismatch {
+                claimed: claimed.to_lowercase(),
+                actual: actual.to_string(),
+            });
+        }
+        if let Some(id) = song_id
+            && !self.songs.is_empty()
+            && !self.songs.contains_key(&id)
+        {
+            warnings.push(NcmError::Metadata(format!(
+                "映射中没有歌曲 {} 的元数据",
+                id
+            )));
+        }
+
+        Ok(OpenedTrack {
+            format: container.map_or_else(|| metadata.format(), str::to_string),
+            version: None,
+            key_set: None,
+            metadata,
+            raw_metadata: None,
+            cover: None,
+            warnings,
+            audio: Box::new(audio),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: u64, cached_size: u64, zone: &[(u64, u64)]) -> CacheEntry {
+        CacheEntry {
+            path: PathBuf::from("1-320-abc.uc"),
+            index: CacheIndex {
+                size: Some(size),
+                zone: zone.to_vec(),
+                ..Default::default()
+            },
+            cached_size,
+        }
+    }
+
+    #[test]
+    fn missing_bytes() {
+        // 没有范围时按文件大小计算
+        assert_eq!(entry(1000, 1000, &[]).missing_bytes(), Some(0));
+        assert_eq!(entry(1000, 400, &[]).missing_bytes(), Some(600));
+        assert!(!entry(1000, 400, &[]).is_complete());
+
+        // 乱序且重叠、包含在其他范围内的范围只统计一次
+        let zones = [(500, 599), (0, 99), (50, 149), (60, 70)];
+        assert_eq!(entry(1000, 1000, &zones).missing_bytes(), Some(750));
+        assert_eq!(entry(1000, 1000, &[(0, 999)]).missing_bytes(), Some(0));
+        assert!(entry(1000, 1000, &[(0, 499), (500, 999)]).is_complete());
+
+        // 超出实际文件大小或完整大小的部分不算
+        assert_eq!(entry(1000, 950, &[(900, 1999)]).missing_bytes(), Some(950));
+        assert_eq!(entry(1000, 2000, &[(0, u64::MAX)]).missing_bytes(), Some(0));
+
+        let mut unknown = entry(0, 10, &[]);
+        unknown.index.size = None;
+        assert_eq!(unknown.missing_bytes(), None);
+        assert!(unknown.is_complete());
+    }
+
+    #[test]
+    fn index_from_json() {
+        let index = CacheIndex::from_json(
+            r#"{"musicId":"1234567","br":320000,"md5":"abc","fileSize":4096,
+                "zone":["0 1023","2048 4095"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            index,
+            CacheIndex {
+                song_id: Some(1234567),
+                bitrate: Some(320000),
+                md5: Some("abc".to_string()),
+                size: Some(4096),
+                zone: vec![(0, 1023), (2048, 4095)],
+            }
+        );
+
+        let index = CacheIndex::from_json(r#"{"id":42,"filesize":"100"}"#).unwrap();
+        assert_eq!(index.song_id, Some(42));
+        assert_eq!(index.size, Some(100));
+
+        for zone in [r#"["10 5"]"#, r#"["1"]"#, r#"["1 2 3"]"#, r#"["a b"]"#] {
+            let json = format!(r#"{{"zone":{}}}"#, zone);
+            assert!(
+                matches!(
+                    CacheIndex::from_json(&json),
+                    Err(NcmError::MetadataField { ref path, .. }) if path == "zone"
+                ),
+                "{}",
+                zone
+            );
+        }
+    }
+
+    #[test]
+    fn index_from_file_name() {
+        let index = CacheIndex::from_file_name(Path::new("/cache/1234567-320-0123ABCD.uc"));
+        assert_eq!(
+            index,
+            CacheIndex {
+                song_id: Some(1234567),
+                bitrate: Some(320),
+                md5: Some("0123abcd".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            song_id_from_path(Path::new("1234567-128-ff.uc")),
+            Some(1234567)
+        );
+
+        let index = CacheIndex::from_file_name(Path::new("song.uc"));
+        assert_eq!(index, CacheIndex::default());
+        let index = CacheIndex::from_file_name(Path::new("99-.uc"));
+        assert_eq!(index.song_id, Some(99));
+        assert_eq!(index.bitrate, None);
+        assert_eq!(index.md5, None);
+    }
+}